@@ -31,7 +31,7 @@ fn pile(c: &mut Criterion) {
             |data: Vec<Bytes>| {
                 let tmp_dir = tempfile::tempdir().unwrap();
                 let tmp_pile = tmp_dir.path().join("test.pile");
-                let mut pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+                let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
                 data.iter().for_each(|data| {
                     pile.insert_blob(data).unwrap();
                 });
@@ -40,6 +40,35 @@ fn pile(c: &mut Criterion) {
         );
     });
 
+    #[cfg(feature = "io_uring")]
+    {
+        group.throughput(Throughput::Bytes(RECORD_COUNT as u64 * RECORD_LEN as u64));
+        group.bench_function(BenchmarkId::new("insert io_uring", RECORD_COUNT), |b| {
+            b.iter_batched(
+                || {
+                    let mut rng = rand::thread_rng();
+                    (0..RECORD_COUNT)
+                        .map(|_| {
+                            let mut record = vec![0u8; RECORD_LEN];
+                            rng.fill_bytes(&mut record);
+
+                            Bytes::from_source(record)
+                        })
+                        .collect()
+                },
+                |data: Vec<Bytes>| {
+                    let tmp_dir = tempfile::tempdir().unwrap();
+                    let tmp_pile = tmp_dir.path().join("test.pile");
+                    let pile: Pile<MAX_PILE_SIZE> = Pile::load_with_io_uring(&tmp_pile).unwrap();
+                    data.iter().for_each(|data| {
+                        pile.insert_blob(data).unwrap();
+                    });
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+
     group.throughput(Throughput::Bytes(RECORD_COUNT as u64 * RECORD_LEN as u64));
     group.bench_function(BenchmarkId::new("insert_unvalidated", RECORD_COUNT), |b| {
         b.iter_batched(
@@ -61,7 +90,7 @@ fn pile(c: &mut Criterion) {
             |data: Vec<(Hash, Bytes)>| {
                 let tmp_dir = tempfile::tempdir().unwrap();
                 let tmp_pile = tmp_dir.path().join("test.pile");
-                let mut pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+                let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
                 data.iter().for_each(|(hash, data)| {
                     pile.insert_blob_unvalidated(*hash, data).unwrap();
                 });
@@ -70,8 +99,60 @@ fn pile(c: &mut Criterion) {
         );
     });
 
+    group.throughput(Throughput::Bytes(RECORD_COUNT as u64 * RECORD_LEN as u64));
+    group.bench_function(BenchmarkId::new("insert_many", RECORD_COUNT), |b| {
+        b.iter_batched(
+            || {
+                let mut rng = rand::thread_rng();
+                (0..RECORD_COUNT)
+                    .map(|_| {
+                        let mut record = vec![0u8; RECORD_LEN];
+                        rng.fill_bytes(&mut record);
+
+                        Bytes::from_source(record)
+                    })
+                    .collect()
+            },
+            |data: Vec<Bytes>| {
+                let tmp_dir = tempfile::tempdir().unwrap();
+                let tmp_pile = tmp_dir.path().join("test.pile");
+                let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+                pile.insert_many(&data).unwrap();
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    group.throughput(Throughput::Bytes(RECORD_COUNT as u64 * RECORD_LEN as u64));
+    group.bench_function(BenchmarkId::new("enqueue", RECORD_COUNT), |b| {
+        b.iter_batched(
+            || {
+                let mut rng = rand::thread_rng();
+                (0..RECORD_COUNT)
+                    .map(|_| {
+                        let mut record = vec![0u8; RECORD_LEN];
+                        rng.fill_bytes(&mut record);
+
+                        Bytes::from_source(record)
+                    })
+                    .collect()
+            },
+            |data: Vec<Bytes>| {
+                let tmp_dir = tempfile::tempdir().unwrap();
+                let tmp_pile = tmp_dir.path().join("test.pile");
+                let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+                let queued = pile.with_writer_thread(64);
+                data.into_iter().for_each(|data| {
+                    queued.enqueue(data).unwrap();
+                });
+                queued.shutdown().unwrap();
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
     const FLUSHED_RECORD_COUNT: usize = 1 << 10; // 1k
-    group.throughput(Throughput::Bytes(FLUSHED_RECORD_COUNT as u64 * 1000 as u64));
+    group.throughput(Throughput::Bytes(FLUSHED_RECORD_COUNT as u64 * 1000));
     group.bench_function(BenchmarkId::new("insert flushed", RECORD_COUNT), |b| {
         b.iter_batched(
             || {
@@ -88,7 +169,7 @@ fn pile(c: &mut Criterion) {
             |data: Vec<Bytes>| {
                 let tmp_dir = tempfile::tempdir().unwrap();
                 let tmp_pile = tmp_dir.path().join("test.pile");
-                let mut pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+                let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
                 data.iter().for_each(|data| {
                     pile.insert_blob(data).unwrap();
                     pile.flush().unwrap();
@@ -98,6 +179,33 @@ fn pile(c: &mut Criterion) {
         );
     });
 
+    group.throughput(Throughput::Bytes(FLUSHED_RECORD_COUNT as u64 * 1000));
+    group.bench_function(BenchmarkId::new("insert sync_appended", RECORD_COUNT), |b| {
+        b.iter_batched(
+            || {
+                let mut rng = rand::thread_rng();
+                (0..FLUSHED_RECORD_COUNT)
+                    .map(|_| {
+                        let mut record = vec![0u8; RECORD_LEN];
+                        rng.fill_bytes(&mut record);
+
+                        Bytes::from_source(record)
+                    })
+                    .collect()
+            },
+            |data: Vec<Bytes>| {
+                let tmp_dir = tempfile::tempdir().unwrap();
+                let tmp_pile = tmp_dir.path().join("test.pile");
+                let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+                data.iter().for_each(|data| {
+                    pile.insert_blob(data).unwrap();
+                    pile.sync_appended().unwrap();
+                });
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
     group.throughput(Throughput::Bytes(RECORD_COUNT as u64 * RECORD_LEN as u64));
     group.bench_function(BenchmarkId::new("load", RECORD_COUNT), |b| {
         b.iter_batched(
@@ -105,7 +213,7 @@ fn pile(c: &mut Criterion) {
                 let mut rng = rand::thread_rng();
                 let tmp_dir = tempfile::tempdir().unwrap();
                 let tmp_pile = tmp_dir.path().join("test.pile");
-                let mut pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+                let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
 
                 (0..RECORD_COUNT).for_each(|_| {
                     let mut record = vec![0u8; RECORD_LEN];
@@ -128,6 +236,100 @@ fn pile(c: &mut Criterion) {
         );
     });
 
+    group.throughput(Throughput::Bytes(RECORD_COUNT as u64 * RECORD_LEN as u64));
+    group.bench_function(BenchmarkId::new("load with index", RECORD_COUNT), |b| {
+        b.iter_batched(
+            || {
+                let mut rng = rand::thread_rng();
+                let tmp_dir = tempfile::tempdir().unwrap();
+                let tmp_pile = tmp_dir.path().join("test.pile");
+                let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+
+                (0..RECORD_COUNT).for_each(|_| {
+                    let mut record = vec![0u8; RECORD_LEN];
+                    rng.fill_bytes(&mut record);
+
+                    let data = Bytes::from_source(record);
+                    pile.insert_blob(&data).unwrap();
+                });
+
+                pile.flush_with_index().unwrap();
+
+                tmp_dir
+            },
+            |tmp_dir: TempDir| {
+                let tmp_pile = tmp_dir.path().join("test.pile");
+                let _pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+                drop(tmp_dir)
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    #[cfg(feature = "rayon")]
+    {
+        group.throughput(Throughput::Bytes(RECORD_COUNT as u64 * RECORD_LEN as u64));
+        group.bench_function(BenchmarkId::new("load_parallel", RECORD_COUNT), |b| {
+            b.iter_batched(
+                || {
+                    let mut rng = rand::thread_rng();
+                    let tmp_dir = tempfile::tempdir().unwrap();
+                    let tmp_pile = tmp_dir.path().join("test.pile");
+                    let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+
+                    (0..RECORD_COUNT).for_each(|_| {
+                        let mut record = vec![0u8; RECORD_LEN];
+                        rng.fill_bytes(&mut record);
+
+                        let data = Bytes::from_source(record);
+                        pile.insert_blob(&data).unwrap();
+                    });
+
+                    pile.flush().unwrap();
+
+                    tmp_dir
+                },
+                |tmp_dir: TempDir| {
+                    let tmp_pile = tmp_dir.path().join("test.pile");
+                    let _pile: Pile<MAX_PILE_SIZE> = Pile::load_parallel(&tmp_pile).unwrap();
+                    drop(tmp_dir)
+                },
+                BatchSize::PerIteration,
+            );
+        });
+
+        group.throughput(Throughput::Bytes(RECORD_COUNT as u64 * RECORD_LEN as u64));
+        group.bench_function(BenchmarkId::new("validate_all_parallel", RECORD_COUNT), |b| {
+            b.iter_batched(
+                || {
+                    let mut rng = rand::thread_rng();
+                    let tmp_dir = tempfile::tempdir().unwrap();
+                    let tmp_pile = tmp_dir.path().join("test.pile");
+                    let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+
+                    (0..RECORD_COUNT).for_each(|_| {
+                        let mut record = vec![0u8; RECORD_LEN];
+                        rng.fill_bytes(&mut record);
+
+                        let data = Bytes::from_source(record);
+                        pile.insert_blob(&data).unwrap();
+                    });
+
+                    pile.flush().unwrap();
+                    drop(pile);
+
+                    let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+                    (pile, tmp_dir)
+                },
+                |(pile, tmp_dir): (Pile<MAX_PILE_SIZE>, TempDir)| {
+                    assert!(pile.validate_all_parallel().unwrap().is_empty());
+                    drop(tmp_dir)
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+
     /*
     group.throughput(Throughput::Elements(1));
     group.bench_function("read random records", |b| {
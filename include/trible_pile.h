@@ -0,0 +1,39 @@
+/* C interface of trible-pile, built with the `ffi` feature, see src/ffi.rs. */
+
+#ifndef TRIBLE_PILE_H
+#define TRIBLE_PILE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct FfiPile FfiPile;
+
+#define PILE_OK 0
+#define PILE_NOT_FOUND 1
+#define PILE_ERR_NULL -1
+#define PILE_ERR_IO -2
+#define PILE_ERR_INVALID_PATH -3
+#define PILE_ERR_CORRUPTED -4
+#define PILE_ERR_TOO_LARGE -5
+#define PILE_ERR_LOCKED -6
+#define PILE_ERR_READ_ONLY -7
+#define PILE_ERR_POISONED -8
+#define PILE_ERR_PANIC -9
+#define PILE_ERR_OTHER -10
+
+int pile_load(const char *path, size_t max_size, FfiPile **out);
+int pile_insert(const FfiPile *pile, const uint8_t *data, size_t len, uint8_t hash_out[32]);
+/* The returned bytes stay valid until the pile is freed. */
+int pile_get(const FfiPile *pile, const uint8_t hash[32], const uint8_t **data_out, size_t *len_out);
+int pile_flush(const FfiPile *pile);
+void pile_free(FfiPile *pile);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
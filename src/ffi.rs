@@ -0,0 +1,271 @@
+//! A C ABI for embedding piles in non-Rust hosts, enabled by the `ffi` feature.
+//!
+//! Piles are passed around as opaque `FfiPile` pointers created by `pile_load`
+//! and destroyed by `pile_free`. Hashes are 32 byte arrays, blobs are pointer
+//! and length pairs. Every function returns `PILE_OK`, `PILE_NOT_FOUND`, or one
+//! of the negative `PILE_ERR_*` codes, panics are caught and reported as
+//! `PILE_ERR_PANIC` instead of unwinding into the host.
+//!
+//! The crate is built as a Rust library, C hosts can link it with e.g.
+//! `cargo rustc --release --features ffi --crate-type staticlib`.
+//! `include/trible_pile.h` declares the functions for C and C++.
+
+use std::ffi::{c_char, c_int, CStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+
+use anybytes::Bytes;
+
+use crate::{FlushError, GetError, Hash, InsertError, LoadError, Pile};
+
+/// The pile behind the opaque pointers, its size limit is passed to `pile_load`.
+pub type FfiPile = Pile<0>;
+
+pub const PILE_OK: c_int = 0;
+/// `pile_get` didn't find the blob.
+pub const PILE_NOT_FOUND: c_int = 1;
+/// A pointer argument was null.
+pub const PILE_ERR_NULL: c_int = -1;
+pub const PILE_ERR_IO: c_int = -2;
+/// The path is not valid UTF-8.
+pub const PILE_ERR_INVALID_PATH: c_int = -3;
+/// The file is not a valid pile, or a blob doesn't match its hash.
+pub const PILE_ERR_CORRUPTED: c_int = -4;
+/// The pile or the blob exceeds the size limit.
+pub const PILE_ERR_TOO_LARGE: c_int = -5;
+/// Another writing pile has the file open, see `LoadError::AlreadyLocked`.
+pub const PILE_ERR_LOCKED: c_int = -6;
+pub const PILE_ERR_READ_ONLY: c_int = -7;
+/// A thread panicked while holding a lock of the pile.
+pub const PILE_ERR_POISONED: c_int = -8;
+pub const PILE_ERR_PANIC: c_int = -9;
+pub const PILE_ERR_OTHER: c_int = -10;
+
+fn load_error_code(err: &LoadError) -> c_int {
+    match err {
+        LoadError::IoError(_) => PILE_ERR_IO,
+        LoadError::MagicMarkerError
+        | LoadError::HeaderError
+        | LoadError::UnexpectedEndOfFile { .. }
+        | LoadError::FileLengthError { .. }
+        | LoadError::ValidationFailed(_) => PILE_ERR_CORRUPTED,
+        LoadError::PileTooLarge | LoadError::CapacityTooSmall { .. } => PILE_ERR_TOO_LARGE,
+        LoadError::InsertError(err) => insert_error_code(err),
+        LoadError::AlreadyLocked => PILE_ERR_LOCKED,
+        _ => PILE_ERR_OTHER,
+    }
+}
+
+fn insert_error_code(err: &InsertError) -> c_int {
+    match err {
+        InsertError::IoError(_) => PILE_ERR_IO,
+        InsertError::PoisonError => PILE_ERR_POISONED,
+        InsertError::PileTooLarge | InsertError::BlobTooLarge { .. } => PILE_ERR_TOO_LARGE,
+        InsertError::ReadOnly => PILE_ERR_READ_ONLY,
+        _ => PILE_ERR_OTHER,
+    }
+}
+
+fn get_error_code(err: &GetError) -> c_int {
+    match err {
+        GetError::ValidationError { .. } => PILE_ERR_CORRUPTED,
+        GetError::PoisonError => PILE_ERR_POISONED,
+        GetError::InsertError(err) => insert_error_code(err),
+        _ => PILE_ERR_OTHER,
+    }
+}
+
+fn flush_error_code(err: &FlushError) -> c_int {
+    match err {
+        FlushError::IoError(_) => PILE_ERR_IO,
+        FlushError::PoisonError => PILE_ERR_POISONED,
+        FlushError::ReadOnly => PILE_ERR_READ_ONLY,
+        FlushError::PileTooLarge => PILE_ERR_TOO_LARGE,
+        _ => PILE_ERR_OTHER,
+    }
+}
+
+// Panics must not unwind into the host, which is undefined behaviour.
+fn catch_panics(f: impl FnOnce() -> c_int) -> c_int {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(PILE_ERR_PANIC)
+}
+
+/// Opens the pile at the nul-terminated UTF-8 `path` with a size limit of
+/// `max_size` bytes, creating the file if it doesn't exist, see `Pile::load`.
+///
+/// On success a pointer to the pile is stored in `out`, which has to be
+/// released with `pile_free`.
+///
+/// # Safety
+///
+/// `path` has to point to a nul-terminated string and `out` to writable memory
+/// for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn pile_load(
+    path: *const c_char,
+    max_size: usize,
+    out: *mut *mut FfiPile,
+) -> c_int {
+    catch_panics(|| {
+        if path.is_null() || out.is_null() {
+            return PILE_ERR_NULL;
+        }
+        let Ok(path) = CStr::from_ptr(path).to_str() else {
+            return PILE_ERR_INVALID_PATH;
+        };
+        match FfiPile::load_with_capacity(Path::new(path), max_size) {
+            Ok(pile) => {
+                *out = Box::into_raw(Box::new(pile));
+                PILE_OK
+            }
+            Err(err) => load_error_code(&err),
+        }
+    })
+}
+
+/// Stores the `len` bytes at `data` and writes their hash to `hash_out`.
+///
+/// # Safety
+///
+/// `pile` has to come from `pile_load` and not be freed yet, `data` has to
+/// point to `len` readable bytes, or may be null if `len` is 0, and `hash_out`
+/// to 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pile_insert(
+    pile: *const FfiPile,
+    data: *const u8,
+    len: usize,
+    hash_out: *mut u8,
+) -> c_int {
+    catch_panics(|| {
+        if pile.is_null() || hash_out.is_null() || (data.is_null() && len > 0) {
+            return PILE_ERR_NULL;
+        }
+        let value = if len == 0 {
+            Bytes::empty()
+        } else {
+            Bytes::from_source(std::slice::from_raw_parts(data, len).to_vec())
+        };
+        match (*pile).insert_blob(&value) {
+            Ok(hash) => {
+                std::ptr::copy_nonoverlapping(hash.as_ptr(), hash_out, hash.len());
+                PILE_OK
+            }
+            Err(err) => insert_error_code(&err),
+        }
+    })
+}
+
+/// Looks up the blob with the 32 byte `hash` and stores a pointer to its bytes
+/// in `data_out` and their length in `len_out`, or returns `PILE_NOT_FOUND`.
+///
+/// The bytes are validated on first access and belong to the pile,
+/// they stay valid and unchanged until the pile is freed.
+///
+/// # Safety
+///
+/// `pile` has to come from `pile_load` and not be freed yet, `hash` has to point
+/// to 32 readable bytes, `data_out` and `len_out` to writable memory for a pointer
+/// and a length.
+#[no_mangle]
+pub unsafe extern "C" fn pile_get(
+    pile: *const FfiPile,
+    hash: *const u8,
+    data_out: *mut *const u8,
+    len_out: *mut usize,
+) -> c_int {
+    catch_panics(|| {
+        if pile.is_null() || hash.is_null() || data_out.is_null() || len_out.is_null() {
+            return PILE_ERR_NULL;
+        }
+        let hash: Hash = std::ptr::read_unaligned(hash.cast());
+        // The index holds on to the bytes of every valid blob, and the functions
+        // of this module never remove blobs, so the pointer stays valid after
+        // `bytes` is dropped, until the pile is freed.
+        match (*pile).get_blob(&hash) {
+            Ok(Some(bytes)) => {
+                *data_out = bytes.as_ptr();
+                *len_out = bytes.len();
+                PILE_OK
+            }
+            Ok(None) => PILE_NOT_FOUND,
+            Err(err) => get_error_code(&err),
+        }
+    })
+}
+
+/// Syncs all appended blobs to disk, see `Pile::flush`.
+///
+/// # Safety
+///
+/// `pile` has to come from `pile_load` and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn pile_flush(pile: *const FfiPile) -> c_int {
+    catch_panics(|| {
+        if pile.is_null() {
+            return PILE_ERR_NULL;
+        }
+        match (*pile).flush() {
+            Ok(()) => PILE_OK,
+            Err(err) => flush_error_code(&err),
+        }
+    })
+}
+
+/// Closes a pile, null pointers are ignored.
+///
+/// # Safety
+///
+/// `pile` has to come from `pile_load` and may only be freed once,
+/// pointers returned by `pile_get` are invalid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pile_free(pile: *mut FfiPile) {
+    if !pile.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(pile))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn round_trip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = CString::new(tmp_dir.path().join("test.pile").to_str().unwrap()).unwrap();
+
+        unsafe {
+            let mut pile = std::ptr::null_mut();
+            assert_eq!(pile_load(path.as_ptr(), 1 << 20, &mut pile), PILE_OK);
+            let mut locked = std::ptr::null_mut();
+            assert_eq!(pile_load(path.as_ptr(), 1 << 20, &mut locked), PILE_ERR_LOCKED);
+
+            let blob = [1u8; 100];
+            let mut hash = [0u8; 32];
+            assert_eq!(pile_insert(pile, blob.as_ptr(), blob.len(), hash.as_mut_ptr()), PILE_OK);
+            assert_eq!(pile_flush(pile), PILE_OK);
+
+            let mut data = std::ptr::null();
+            let mut len = 0;
+            assert_eq!(pile_get(pile, hash.as_ptr(), &mut data, &mut len), PILE_OK);
+            assert_eq!(std::slice::from_raw_parts(data, len), &blob[..]);
+            assert_eq!(
+                pile_get(pile, [0u8; 32].as_ptr(), &mut data, &mut len),
+                PILE_NOT_FOUND
+            );
+            assert_eq!(
+                pile_insert(pile, std::ptr::null(), 1, hash.as_mut_ptr()),
+                PILE_ERR_NULL
+            );
+            let too_large = vec![0u8; 1 << 20];
+            assert_eq!(
+                pile_insert(pile, too_large.as_ptr(), too_large.len(), hash.as_mut_ptr()),
+                PILE_ERR_TOO_LARGE
+            );
+
+            pile_free(pile);
+            pile_free(std::ptr::null_mut());
+        }
+    }
+}
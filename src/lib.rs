@@ -1,403 +1,7225 @@
 use anybytes::Bytes;
+use crossbeam_channel::{Receiver, Sender};
 pub use blake3::Hasher as Blake3;
-use digest::Digest;
+use digest::{consts::U32, Digest};
 use hex_literal::hex;
 use memmap2::MmapOptions;
+use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::path::Path;
-use std::ptr::slice_from_raw_parts;
+use std::marker::PhantomData;
+use std::ops::{Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, PoisonError, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{collections::HashMap, io::Write};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, SystemTimeError, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use zerocopy::byteorder::little_endian::{U32 as LeU32, U64};
 use zerocopy::{Immutable, IntoBytes, KnownLayout, TryFromBytes};
 
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
 pub type Id = [u8; 16];
 pub type Hash = [u8; 32];
 
+/// The key of an encrypted pile, see `Pile::load_encrypted`.
+#[cfg(feature = "encrypt")]
+pub type EncryptionKey = [u8; 32];
+
+/// The longest name of a head in bytes, see `Pile::set_head`.
+pub const MAX_HEAD_NAME_LEN: usize = 16;
+
+/// The version of the file format written by this crate.
+///
+/// Files start with a version record, files without one are version 0.
+/// Since version 2 blob records carry a CRC32C checksum in their padding,
+/// since version 3 the version record can set a record alignment other than 64,
+/// since version 4 files can contain footer and head records,
+/// since version 5 encrypted piles keep the nonce of a blob in its record header.
+pub const FORMAT_VERSION: u8 = 5;
+
+// Readers of older versions fail on footer and head records, so they aren't
+// appended to older files, which `compact` rewrites in the current version.
+const FOOTER_AND_HEAD_VERSION: u8 = 4;
+
+/// The record alignment of piles that don't choose one, see `Pile::load_with_alignment`.
+pub const DEFAULT_ALIGNMENT: usize = 64;
+
+const MAGIC_MARKER_VERSION: Id = hex!("6F1D2C9E84B04A7D93E5C0A1B7F64E28");
 const MAGIC_MARKER_BLOB: Id = hex!("1E08B022FF2F47B6EBACF1D68EB35D96");
 const MAGIC_MARKER_BRANCH: Id = hex!("2BC991A7F5D5D2A3A468C53B0AA03504");
+const MAGIC_MARKER_TOMBSTONE: Id = hex!("F3B731BE942E242521231513E1EF2398");
+const MAGIC_MARKER_BLOB_ZSTD: Id = hex!("9C5A0E27B1D34F8E6A2C7B19D0E84F63");
+const MAGIC_MARKER_FOOTER: Id = hex!("4D83B6F10E2A5C97D41F8B3E6C0A92D5");
+const MAGIC_MARKER_HEAD: Id = hex!("B17E4C2A93D05F68E2A1C7B4096D3E8F");
+const MAGIC_MARKER_INDEX_FILE: Id = hex!("5A0F3D8B2E714C96B8D1E47A93C6025F");
 
+const INDEX_FILE_COMPRESSED: u8 = 1;
+const INDEX_FILE_CHECKSUM: u8 = 2;
 
-struct AppendFile {
-    file: File,
-    length: usize,
-}
+const MIN_GROWABLE_MAPPING: usize = 1 << 20;
+const STREAM_CHUNK_SIZE: usize = 1 << 16;
+const INDEX_SHARDS: usize = 16;
+const BLOOM_BITS_PER_BLOB: usize = 16;
+const BLOOM_PROBES: usize = 4;
 
-#[derive(Debug, Clone, Copy)]
-enum ValidationState {
-    Unvalidated,
-    Validated,
-    Invalid,
-}
+const VERSION_FLAG_ENCRYPTED: u8 = 1;
+#[cfg(feature = "encrypt")]
+const NONCE_LEN: usize = 24;
+// The authentication tag behind the ciphertext, the nonce is part of the record header.
+#[cfg(feature = "encrypt")]
+const ENCRYPTION_OVERHEAD: usize = 16;
+// Blob headers of encrypted piles are followed by the nonce,
+// so their records are aligned to at least 128 bytes to make room for it.
+#[cfg(feature = "encrypt")]
+const ENCRYPTED_ALIGNMENT: usize = 128;
+#[cfg(feature = "encrypt")]
+const PLAINTEXT_CACHE_SIZE: usize = 64 << 20;
 
-struct IndexEntry {
-    bytes: Bytes,
-    state: ValidationState,
+// Every record starts at a multiple of the alignment. Headers are zero padded
+// to the alignment if it is larger than them, so that blob bytes are aligned too.
+fn header_length(alignment: usize) -> usize {
+    alignment.max(64)
 }
 
-#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
-#[repr(C)]
-struct BranchHeader {
-    magic_marker: Id,
-    branch_id: Id,
-    hash: Hash,
+fn padding_for_blob(length: usize, alignment: usize) -> usize {
+    (alignment - (length % alignment)) % alignment
 }
 
-impl BranchHeader {
-    fn new(branch_id: Id, hash: Hash) -> Self {
-        Self {
-            magic_marker: MAGIC_MARKER_BRANCH,
-            branch_id,
-            hash,
-        }
-    }
+// Saturates instead of overflowing, the result is compared against the size limit anyway.
+fn blob_record_length(length: usize, alignment: usize) -> usize {
+    header_length(alignment)
+        .saturating_add(length)
+        .saturating_add(padding_for_blob(length, alignment))
 }
 
-#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
-#[repr(C)]
-struct BlobHeader {
-    magic_marker: Id,
-    timestamp: u64,
-    length: u64,
-    hash: Hash,
+// Blobs with at least 4 bytes of padding store a CRC32C of their stored bytes
+// at the start of the padding. The blob header has no room left for a checksum,
+// so blobs with less padding have none and are always validated against their hash.
+fn checksum_for_blob(value: &[u8], alignment: usize) -> Option<u32> {
+    (padding_for_blob(value.len(), alignment) >= 4).then(|| crc32c::crc32c(value))
 }
 
-impl BlobHeader {
-    fn new(timestamp: u64, length: u64, hash: Hash) -> Self {
-        Self {
-            magic_marker: MAGIC_MARKER_BLOB,
-            timestamp,
-            length,
-            hash,
-        }
+fn padding_with_checksum(checksum: Option<u32>, padding: usize) -> Vec<u8> {
+    let mut bytes = vec![0; padding];
+    if let Some(checksum) = checksum {
+        bytes[0..4].copy_from_slice(&checksum.to_le_bytes());
     }
+    bytes
 }
 
-pub struct Pile<const MAX_PILE_SIZE: usize> {
-    file: Mutex<AppendFile>,
-    mmap: Arc<memmap2::MmapRaw>,
-    index: RwLock<HashMap<Hash, Mutex<IndexEntry>>>,
-    branches: RwLock<HashMap<Id, Hash>>,
+// Returns the header followed by the zeroes that pad it to `header_length`.
+fn padded_header(header: &[u8], alignment: usize) -> Vec<u8> {
+    let mut bytes = header.to_vec();
+    bytes.resize(header_length(alignment), 0);
+    bytes
 }
 
-#[derive(Debug)]
-pub enum LoadError {
-    IoError(std::io::Error),
-    MagicMarkerError,
-    HeaderError,
-    UnexpectedEndOfFile,
-    FileLengthError,
-    PileTooLarge,
+// Identifies the key of an encrypted pile without revealing it,
+// so that opening a pile with the wrong key fails right away.
+#[cfg(feature = "encrypt")]
+fn key_check(key: &EncryptionKey) -> [u8; 16] {
+    let hash = blake3::keyed_hash(key, b"trible-pile key check");
+    hash.as_bytes()[0..16].try_into().unwrap()
 }
 
-impl From<std::io::Error> for LoadError {
-    fn from(err: std::io::Error) -> Self {
-        Self::IoError(err)
-    }
-}
+// Encrypted blobs are sealed as a random nonce followed by the ciphertext and its tag,
+// records keep the nonce in their header, see `Pile::split_stored`.
+// The hash is authenticated too, so a ciphertext can't be moved to another record.
+#[cfg(feature = "encrypt")]
+fn encrypt_blob(key: &EncryptionKey, hash: &Hash, plaintext: &[u8]) -> Bytes {
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use rand::RngCore;
 
-#[derive(Debug)]
-pub enum InsertError {
-    IoError(std::io::Error),
-    PoisonError,
-    PileTooLarge,
+    let cipher = chacha20poly1305::XChaCha20Poly1305::new(key.into());
+    let mut nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let payload = Payload {
+        msg: plaintext,
+        aad: hash,
+    };
+    // Only fails for blobs of more than 256 GiB.
+    let ciphertext = cipher
+        .encrypt(&nonce.into(), payload)
+        .expect("blob too large to encrypt");
+    let mut stored = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    stored.extend_from_slice(&nonce);
+    stored.extend_from_slice(&ciphertext);
+    Bytes::from_source(stored)
 }
 
-impl From<std::io::Error> for InsertError {
-    fn from(err: std::io::Error) -> Self {
-        Self::IoError(err)
-    }
-}
+#[cfg(feature = "encrypt")]
+fn decrypt_blob(
+    key: &EncryptionKey,
+    hash: &Hash,
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> Option<Bytes> {
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
 
-impl<T> From<PoisonError<T>> for InsertError {
-    fn from(_err: PoisonError<T>) -> Self {
-        Self::PoisonError
+    if nonce.len() != NONCE_LEN || ciphertext.len() < ENCRYPTION_OVERHEAD {
+        return None;
     }
+    let cipher = chacha20poly1305::XChaCha20Poly1305::new(key.into());
+    let payload = Payload {
+        msg: ciphertext,
+        aad: hash,
+    };
+    let plaintext = cipher.decrypt(nonce.into(), payload).ok()?;
+    Some(Bytes::from_source(plaintext))
 }
 
-#[derive(Debug)]
-pub enum GetError {
-    PoisonError,
-    ValidationError(Bytes),
+fn now_in_ms() -> Result<u64, SystemTimeError> {
+    ms_since_epoch(SystemTime::now())
 }
 
-impl<T> From<PoisonError<T>> for GetError {
-    fn from(_err: PoisonError<T>) -> Self {
-        Self::PoisonError
-    }
+// Fails for times before the epoch, e.g. after restoring a VM snapshot with a wrong clock.
+fn ms_since_epoch(time: SystemTime) -> Result<u64, SystemTimeError> {
+    let since_epoch = time.duration_since(UNIX_EPOCH)?;
+    Ok(since_epoch.as_millis() as u64)
 }
 
-#[derive(Debug)]
-pub enum FlushError {
-    IoError(std::io::Error),
-    PoisonError,
-}
+// Also used for hash prefixes, which are enough to correlate trace events.
+struct HexHash<'a>(&'a [u8]);
 
-impl From<std::io::Error> for FlushError {
-    fn from(err: std::io::Error) -> Self {
-        Self::IoError(err)
+impl fmt::Display for HexHash<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02X}")?;
+        }
+        Ok(())
     }
 }
 
-impl<T> From<PoisonError<T>> for FlushError {
-    fn from(_err: PoisonError<T>) -> Self {
-        Self::PoisonError
-    }
+struct AppendFile {
+    file: File,
+    length: usize,
+    unsynced_records: usize,
+    last_synced_length: usize,
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    ring: Option<io_uring::IoUring>,
 }
 
-//TODO Handle incomplete writes by truncating the file
-//TODO Add the ability to skip corrupted blobs
-impl<const MAX_PILE_SIZE: usize> Pile<MAX_PILE_SIZE> {
-    pub fn load(path: &Path) -> Result<Self, LoadError> {
-        let file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&path)?;
-        let file_len = file.metadata()?.len() as usize;
-        if file_len > MAX_PILE_SIZE {
-            return Err(LoadError::PileTooLarge);
-        }
-        let mmap = MmapOptions::new()
-            .len(MAX_PILE_SIZE)
-            .map_raw_read_only(&file)?;
-        let mmap = Arc::new(mmap);
-        let mut bytes = unsafe {
-            let written_slice = slice_from_raw_parts(mmap.as_ptr(), file_len)
-                .as_ref()
-                .unwrap();
-            Bytes::from_raw_parts(written_slice, mmap.clone())
-        };
-        if bytes.len() % 64 != 0 {
-            return Err(LoadError::FileLengthError);
+impl AppendFile {
+    // Appends the parts of a record in order. With io_uring the writes
+    // are submitted together, which takes a single syscall.
+    fn write_record(&mut self, parts: &[&[u8]]) -> Result<(), std::io::Error> {
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            let written = write_with_ring(ring, &mut self.file, parts);
+            if let Err(err) = &written {
+                // Writes the kernel didn't take are still queued, so the ring is dropped
+                // instead of submitting them with the next record.
+                log::warn!("falling back to regular writes: {err}");
+                self.ring = None;
+            }
+            return written;
         }
+        for part in parts {
+            self.file.write_all(part)?;
+        }
+        Ok(())
+    }
 
-        let mut index = HashMap::new();
-        let mut branches = HashMap::new();
-
-        while bytes.len() > 0 {
-            if bytes.len() < 16 {
-                return Err(LoadError::UnexpectedEndOfFile);
+    // Counts appended records and syncs the file if the durability policy asks for it.
+    // Callers index the records first, so a failed sync doesn't leave them unindexed.
+    fn appended(&mut self, records: usize, durability: Durability) -> Result<(), std::io::Error> {
+        self.unsynced_records += records;
+        if let Durability::EverySync(n) = durability {
+            if self.unsynced_records >= n {
+                self.sync()?;
             }
-            let magic = bytes[0..16].try_into().unwrap();
-            match magic {
-                MAGIC_MARKER_BLOB => {
-                    let Ok(header) = bytes.view_prefix::<BlobHeader>() else {
-                        return Err(LoadError::HeaderError);
-                    };
-                    let hash = header.hash;
-                    let length = header.length as usize;
-                    let Some(blob_bytes) = bytes.take_prefix(length) else {
-                        return Err(LoadError::UnexpectedEndOfFile);
-                    };
-        
-                    let Some(_) = bytes.take_prefix(64 - (length % 64)) else {
-                        return Err(LoadError::UnexpectedEndOfFile);
-                    };
-        
-                    let blob = IndexEntry {
-                        state: ValidationState::Unvalidated,
-                        bytes: blob_bytes,
-                    };
-                    index.insert(hash, Mutex::new(blob));
-                }
-                MAGIC_MARKER_BRANCH => {
-                    let Ok(header) = bytes.view_prefix::<BranchHeader>() else {
-                        return Err(LoadError::HeaderError);
-                    };
-                    let branch_id = header.branch_id;
-                    let hash = header.hash;
-                    branches.insert(branch_id, hash);
-                }
-                _ => return Err(LoadError::MagicMarkerError)
-            };
         }
-
-        let index = RwLock::new(index);
-        let branches = RwLock::new(branches);
-
-        let file = Mutex::new(AppendFile {
-            file,
-            length: file_len,
-        });
-
-        Ok(Self { file, mmap, index, branches })
+        Ok(())
     }
 
-    #[must_use]
-    fn insert_blob_raw(
-        &mut self,
-        hash: Hash,
-        validation: ValidationState,
-        value: &Bytes,
-    ) -> Result<Bytes, InsertError> {
-        let mut append = self.file.lock().unwrap();
+    fn sync(&mut self) -> Result<(), std::io::Error> {
+        self.file.sync_data()?;
+        self.unsynced_records = 0;
+        self.last_synced_length = self.length;
+        Ok(())
+    }
 
-        let old_length = append.length;
-        let padding = 64 - (value.len() % 64);
+    // Writes out the bytes appended since the last sync, without the file metadata.
+    #[cfg(target_os = "linux")]
+    fn sync_appended(&mut self) -> Result<(), std::io::Error> {
+        use std::os::unix::io::AsRawFd;
 
-        let new_length = old_length + 64 + value.len() + padding;
-        if new_length > MAX_PILE_SIZE {
-            return Err(InsertError::PileTooLarge);
+        let offset = self.last_synced_length;
+        let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+            | libc::SYNC_FILE_RANGE_WRITE
+            | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+        let result = unsafe {
+            libc::sync_file_range(
+                self.file.as_raw_fd(),
+                offset as libc::off64_t,
+                (self.length - offset) as libc::off64_t,
+                flags,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
         }
+        self.last_synced_length = self.length;
+        Ok(())
+    }
 
-        append.length = new_length;
+    #[cfg(not(target_os = "linux"))]
+    fn sync_appended(&mut self) -> Result<(), std::io::Error> {
+        self.sync()
+    }
+}
 
-        let now_in_sys = SystemTime::now();
-        let now_since_epoch = now_in_sys
-            .duration_since(UNIX_EPOCH)
-            .expect("time went backwards");
-        let now_in_ms = now_since_epoch.as_millis();
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+const RING_ENTRIES: u32 = 8;
 
-        let header = BlobHeader::new(now_in_ms as u64, value.len() as u64, hash);
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+fn write_with_ring(
+    ring: &mut io_uring::IoUring,
+    file: &mut File,
+    parts: &[&[u8]],
+) -> Result<(), std::io::Error> {
+    use io_uring::{opcode, squeue, types};
+    use std::os::unix::io::AsRawFd;
 
-        append.file.write_all(header.as_bytes())?;
-        append.file.write_all(&value)?;
-        append.file.write_all(&[0; 64][0..padding])?;
+    let too_large = parts.iter().any(|part| part.len() > u32::MAX as usize);
+    if parts.len() > RING_ENTRIES as usize || too_large {
+        for part in parts {
+            file.write_all(part)?;
+        }
+        return Ok(());
+    }
 
-        let written_bytes = unsafe {
-            let written_slice =
-                slice_from_raw_parts(self.mmap.as_ptr().offset(old_length as _), value.len())
-                    .as_ref()
-                    .unwrap();
-            Bytes::from_raw_parts(written_slice, self.mmap.clone())
+    let fd = types::Fd(file.as_raw_fd());
+    for (i, part) in parts.iter().enumerate() {
+        // Linked writes run one after another, and the file is opened for
+        // appending, so they end up in order at the end of the file.
+        let write = opcode::Write::new(fd, part.as_ptr(), part.len() as u32)
+            .offset(u64::MAX)
+            .build()
+            .user_data(i as u64);
+        let write = if i + 1 < parts.len() {
+            write.flags(squeue::Flags::IO_LINK)
+        } else {
+            write
         };
+        // The queue is empty between calls and has room for all parts.
+        unsafe { ring.submission().push(&write) }.expect("submission queue has room");
+    }
 
-        let mut index = self.index.write()?;
-        index.insert(
-            hash,
-            Mutex::new(IndexEntry {
-                state: validation,
-                bytes: written_bytes.clone(),
-            }),
-        );
-
-        Ok(written_bytes)
+    // The kernel reads from `parts` until the writes complete,
+    // so this must not return before all completions were reaped.
+    let mut written = vec![0; parts.len()];
+    let mut error = None;
+    let mut polling = false;
+    let mut pending = parts.len();
+    while pending > 0 {
+        if polling {
+            std::thread::yield_now();
+        } else if let Err(err) = ring.submit_and_wait(pending) {
+            match err.raw_os_error() {
+                // Interrupted waits are retried, a full completion queue is emptied below.
+                Some(libc::EINTR | libc::EAGAIN | libc::EBUSY) => {}
+                // Writes the kernel didn't take never start, those it did still complete,
+                // so their completions are polled for instead of waiting on the ring.
+                _ => {
+                    pending -= ring.submission().len();
+                    polling = true;
+                    error = Some(err);
+                }
+            }
+        }
+        for completion in ring.completion() {
+            pending -= 1;
+            let result = completion.result();
+            if result >= 0 {
+                written[completion.user_data() as usize] = result as usize;
+            } else if result != -libc::ECANCELED {
+                error.get_or_insert(std::io::Error::from_raw_os_error(-result));
+            }
+        }
+    }
+    if let Some(err) = error {
+        return Err(err);
     }
 
-    pub fn insert_blob(&mut self, value: &Bytes) -> Result<Hash, InsertError> {
-        let hash: Hash = Blake3::digest(&value).into();
+    // A short write cancels the writes linked after it, finish them synchronously.
+    if let Some(short) = (0..parts.len()).find(|&i| written[i] < parts[i].len()) {
+        file.write_all(&parts[short][written[short]..])?;
+        for part in &parts[short + 1..] {
+            file.write_all(part)?;
+        }
+    }
+    Ok(())
+}
 
-        let _bytes = self.insert_blob_raw(hash, ValidationState::Validated, value)?;
+#[cfg(unix)]
+fn read_at(file: &File, offset: usize, len: usize) -> Result<Bytes, std::io::Error> {
+    use std::os::unix::fs::FileExt;
 
-        Ok(hash)
-    }
+    let mut buffer = vec![0; len];
+    file.read_exact_at(&mut buffer, offset as u64)?;
+    Ok(Bytes::from_source(buffer))
+}
 
-    pub fn insert_blob_validated(&mut self, hash: Hash, value: &Bytes) -> Result<Bytes, InsertError> {
-        self.insert_blob_raw(hash, ValidationState::Validated, value)
-    }
+#[cfg(windows)]
+fn read_at(file: &File, offset: usize, len: usize) -> Result<Bytes, std::io::Error> {
+    use std::os::windows::fs::FileExt;
 
-    pub fn insert_blob_unvalidated(&mut self, hash: Hash, value: &Bytes) -> Result<Bytes, InsertError> {
-        self.insert_blob_raw(hash, ValidationState::Unvalidated, value)
+    let mut buffer = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        match file.seek_read(&mut buffer[read..], (offset + read) as u64)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
     }
+    Ok(Bytes::from_source(buffer))
+}
 
-    pub fn get_blob(&self, hash: &Hash) -> Result<Option<Bytes>, GetError> {
-        let index = self.index.read().unwrap();
-        let Some(blob) = index.get(hash) else {
-            return Ok(None);
-        };
-        let mut entry = blob.lock().unwrap();
-        match entry.state {
-            ValidationState::Validated => {
-                return Ok(Some(entry.bytes.clone()));
-            }
-            ValidationState::Invalid => {
-                return Err(GetError::ValidationError(entry.bytes.clone()));
-            }
-            ValidationState::Unvalidated => {
-                let computed_hash: Hash = Blake3::digest(&entry.bytes).into();
-                if computed_hash != *hash {
-                    entry.state = ValidationState::Invalid;
-                    return Err(GetError::ValidationError(entry.bytes.clone()));
-                } else {
-                    entry.state = ValidationState::Validated;
-                    return Ok(Some(entry.bytes.clone()));
-                }
-            }
+// Reads the little-endian length in front of a blob of `Pile::import_lengthprefixed`,
+// or returns `None` if the stream ends right before it.
+fn read_length_prefix<R: Read>(reader: &mut R) -> Result<Option<u64>, std::io::Error> {
+    let mut prefix = [0; 8];
+    let mut read = 0;
+    while read < prefix.len() {
+        match reader.read(&mut prefix[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
         }
     }
+    Ok(Some(u64::from_le_bytes(prefix)))
+}
 
-    pub fn commit_branch(&mut self, branch_id: Id, hash: Hash) -> Result<(), InsertError> {
-        let mut append = self.file.lock().unwrap();
+// Reads exactly `remaining` bytes from `inner`, and fails with `UnexpectedEof`
+// if it ends before, so that a truncated stream isn't mistaken for a shorter blob.
+struct ExactReader<R> {
+    inner: R,
+    remaining: u64,
+}
 
-        let new_length = append.length + 64;
-        if new_length > MAX_PILE_SIZE {
-            return Err(InsertError::PileTooLarge);
+impl<R: Read> Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        if self.remaining == 0 {
+            return Ok(0);
         }
+        let len = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 && len > 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
 
-        append.length = new_length;
-
-        let header = BranchHeader::new(branch_id, hash);
-
-        append.file.write_all(header.as_bytes())?;
-
-        let mut branches = self.branches.write()?;
-        branches.insert(
-            branch_id,
-            hash
-        );
+// Where the bytes of a pile are read from.
+enum Storage {
+    Mapped(RwLock<Arc<memmap2::MmapRaw>>),
+    // Piles loaded by `load_no_mmap` read from another handle of the file.
+    Read(File),
+}
 
-        Ok(())
+impl Storage {
+    // Callers only pass ranges of records that were completely written.
+    fn read(&self, offset: usize, len: usize) -> Result<Bytes, std::io::Error> {
+        match self {
+            Self::Mapped(mmap) => {
+                let bytes = unsafe { mapped_bytes(&mmap.read().unwrap(), offset, len) };
+                bytes.ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "range is not mapped")
+                })
+            }
+            Self::Read(file) => read_at(file, offset, len),
+        }
     }
 
-    pub fn get_branch(&self, branch_id: Id) -> Option<Hash>{
-        let branches = self.branches.read().unwrap();
-        branches.get(&branch_id).copied()
+    // The bytes of a record that was just appended, `stored` is what was written.
+    fn written(&self, offset: usize, stored: &Bytes) -> Bytes {
+        match self {
+            // `ensure_mapped` grew the mapping before the record was written.
+            Self::Mapped(mmap) => {
+                let bytes = unsafe { mapped_bytes(&mmap.read().unwrap(), offset, stored.len()) };
+                bytes.expect("appended records are mapped")
+            }
+            Self::Read(_) => stored.clone(),
+        }
     }
 
-    pub fn flush(&self) -> Result<(), FlushError> {
-        let append = self.file.lock()?;
-        append.file.sync_data()?;
+    // Passes the bytes in `range` to `f` in chunks, so that piles without a mapping
+    // don't read the whole range into memory at once.
+    fn for_each_chunk(
+        &self,
+        range: Range<usize>,
+        mut f: impl FnMut(&[u8]) -> Result<(), std::io::Error>,
+    ) -> Result<(), std::io::Error> {
+        let mut offset = range.start;
+        while offset < range.end {
+            let len = (range.end - offset).min(STREAM_CHUNK_SIZE);
+            f(&self.read(offset, len)?)?;
+            offset += len;
+        }
         Ok(())
     }
 }
 
-impl<const MAX_PILE_SIZE: usize> Extend<Bytes> for Pile<MAX_PILE_SIZE> {
-    fn extend<T: IntoIterator<Item = Bytes>>(&mut self, iter: T) {
-        for bytes in iter {
-            let _ = self.insert_blob(&bytes);
-        }
+// Borrows `len` bytes at `offset` from the mapping, the `Bytes` keep the mapping alive.
+// Returns `None` if the range lies outside of the mapping, e.g. because a corrupted
+// index claims a blob that ends past it, instead of reading out of bounds.
+//
+// # Safety
+//
+// The range has to be written to the file and must not change while the `Bytes` exist.
+// Piles only append behind the records they hand out and lock the file against
+// other writers, so this holds as long as nothing modifies the file behind their back.
+unsafe fn mapped_bytes(mmap: &Arc<memmap2::MmapRaw>, offset: usize, len: usize) -> Option<Bytes> {
+    let end = offset.checked_add(len)?;
+    if end > mmap.len() {
+        return None;
     }
+    let slice = std::slice::from_raw_parts(mmap.as_ptr().add(offset), len);
+    Some(Bytes::from_raw_parts(slice, mmap.clone()))
 }
 
-impl<const MAX_PILE_SIZE: usize> Extend<(Hash, Bytes)> for Pile<MAX_PILE_SIZE> {
-    fn extend<T: IntoIterator<Item = (Hash, Bytes)>>(&mut self, iter: T) {
-        for (hash, bytes) in iter {
-            let _ = self.insert_blob_unvalidated(hash, &bytes);
-        }
-    }
+/// How the blobs of a pile are read, see `Pile::advise`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessPattern {
+    /// No particular pattern, the operating system default.
+    #[default]
+    Normal,
+    /// Blobs are read front to back, e.g. when scanning the whole pile.
+    Sequential,
+    /// Blobs are read in no particular order, so readahead doesn't help.
+    Random,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+#[cfg(unix)]
+fn advise_mapping(mmap: &memmap2::MmapRaw, pattern: AccessPattern) -> Result<(), std::io::Error> {
+    let advice = match pattern {
+        AccessPattern::Normal => memmap2::Advice::Normal,
+        AccessPattern::Sequential => memmap2::Advice::Sequential,
+        AccessPattern::Random => memmap2::Advice::Random,
+    };
+    mmap.advise(advice)
+}
 
-    use rand::RngCore;
-    use tempfile;
+#[cfg(not(unix))]
+fn advise_mapping(_mmap: &memmap2::MmapRaw, _pattern: AccessPattern) -> Result<(), std::io::Error> {
+    Ok(())
+}
 
-    #[test]
-    fn load() {
-        const RECORD_LEN: usize = 1 << 10; // 1k
-        const RECORD_COUNT: usize = 1 << 20; // 1M
-        const MAX_PILE_SIZE: usize = 1 << 30; // 100GB
+#[cfg(unix)]
+fn prefetch_range(mmap: &memmap2::MmapRaw, offset: usize, len: usize) -> Result<(), std::io::Error> {
+    mmap.advise_range(memmap2::Advice::WillNeed, offset, len)
+}
 
-        let mut rng = rand::thread_rng();
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let tmp_pile = tmp_dir.path().join("test.pile");
-        let mut pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+#[cfg(not(unix))]
+fn prefetch_range(
+    _mmap: &memmap2::MmapRaw,
+    _offset: usize,
+    _len: usize,
+) -> Result<(), std::io::Error> {
+    Ok(())
+}
 
-        (0..RECORD_COUNT).for_each(|_| {
-            let mut record = Vec::with_capacity(RECORD_LEN);
-            rng.fill_bytes(&mut record);
+/// When a pile syncs appended records to disk on its own,
+/// in addition to explicit calls to `flush`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Only `flush` syncs the file.
+    #[default]
+    None,
+    /// The file is synced when the pile is dropped.
+    OnDrop,
+    /// The file is synced after every `n` appended records.
+    EverySync(usize),
+}
 
-            let data = Bytes::from_source(record);
-            pile.insert_blob(&data).unwrap();
-        });
+/// Whether a blob was checked against its hash yet, see `Pile::validation_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationState {
+    /// The blob will be checked on its next read.
+    Unvalidated,
+    /// The blob matches its hash.
+    Validated,
+    /// The blob doesn't match its hash, reads fail with a `ValidationError`.
+    Invalid,
+}
 
-        pile.flush().unwrap();
+// The outcome of `insert_blob_raw`.
+struct InsertedBlob {
+    // The blob's bytes, pointing into the file unless the pile is encrypted.
+    bytes: Bytes,
+    // Whether the blob was newly stored, `false` if it was already present.
+    added: bool,
+    // Where the blob's record starts, for a duplicate that of the existing record.
+    record_offset: usize,
+}
 
-        drop(pile);
+// A blob `insert_reader` copied to a temporary file, which is removed on drop.
+struct SpooledBlob {
+    file: File,
+    path: PathBuf,
+    hash: Hash,
+    length: usize,
+    checksum: Option<u32>,
+}
 
-        let _pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+impl Drop for SpooledBlob {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!("failed to remove {}: {err}", self.path.display());
+        }
+    }
+}
+
+struct PendingBlob {
+    hash: Hash,
+    timestamp: u64,
+    state: ValidationState,
+    bytes: Bytes,
+}
+
+struct IndexEntry {
+    bytes: Bytes,
+    // The position and length of the blob in the file, before decompression.
+    offset: usize,
+    stored_length: usize,
+    timestamp: u64,
+    state: ValidationState,
+    // The validation cache tick of the last validation or read, see `ValidationCache`.
+    validated_at: u64,
+    #[cfg_attr(not(feature = "zstd"), allow(dead_code))]
+    compressed: bool,
+    #[cfg_attr(not(feature = "encrypt"), allow(dead_code))]
+    encrypted: bool,
+    checksum: Option<u32>,
+    // Piles without a mapping read the bytes on first access, they are empty until then.
+    unread: bool,
+}
+
+impl IndexEntry {
+    // An entry for a blob that was loaded from the file. Mapped piles borrow its bytes
+    // from the mapping, piles without one read them on first access.
+    fn loaded(
+        storage: &Storage,
+        offset: usize,
+        length: usize,
+        timestamp: u64,
+        compressed: bool,
+        encrypted: bool,
+        checksum: Option<u32>,
+    ) -> Result<Self, std::io::Error> {
+        let unread = matches!(storage, Storage::Read(_));
+        Ok(Self {
+            bytes: if unread {
+                Bytes::empty()
+            } else {
+                storage.read(offset, length)?
+            },
+            offset,
+            stored_length: length,
+            timestamp,
+            state: ValidationState::Unvalidated,
+            validated_at: 0,
+            compressed,
+            encrypted,
+            checksum,
+            unread,
+        })
+    }
+
+    // Compressed blobs are decompressed into memory on first access,
+    // blobs that fail to decompress are invalid.
+    fn decompress(&mut self) {
+        #[cfg(feature = "zstd")]
+        if self.compressed {
+            match zstd::decode_all(&self.bytes[..]) {
+                Ok(decompressed) => self.bytes = Bytes::from_source(decompressed),
+                Err(_) => self.state = ValidationState::Invalid,
+            }
+            // The checksum covers the compressed bytes.
+            self.checksum = None;
+            self.compressed = false;
+        }
+    }
+}
+
+#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
+#[repr(C)]
+struct VersionHeader {
+    magic_marker: Id,
+    version: u8,
+    flags: u8,
+    key_check: [u8; 16],
+    // Zero in files written before version 3, which always use the default.
+    alignment: LeU32,
+    reserved: [u8; 26],
+}
+
+impl VersionHeader {
+    fn new(version: u8) -> Self {
+        Self {
+            magic_marker: MAGIC_MARKER_VERSION,
+            version,
+            flags: 0,
+            key_check: [0; 16],
+            alignment: LeU32::new(0),
+            reserved: [0; 26],
+        }
+    }
+
+    fn alignment(&self) -> usize {
+        match self.alignment.get() {
+            0 => DEFAULT_ALIGNMENT,
+            alignment => alignment as usize,
+        }
+    }
+
+    #[cfg(feature = "encrypt")]
+    fn encrypted(version: u8, key: &EncryptionKey) -> Self {
+        Self {
+            flags: VERSION_FLAG_ENCRYPTED,
+            key_check: key_check(key),
+            ..Self::new(version)
+        }
+    }
+}
+
+#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
+#[repr(C)]
+struct BranchHeader {
+    magic_marker: Id,
+    branch_id: Id,
+    hash: Hash,
+}
+
+impl BranchHeader {
+    fn new(branch_id: Id, hash: Hash) -> Self {
+        Self {
+            magic_marker: MAGIC_MARKER_BRANCH,
+            branch_id,
+            hash,
+        }
+    }
+}
+
+#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
+#[repr(C)]
+struct HeadHeader {
+    magic_marker: Id,
+    name: [u8; MAX_HEAD_NAME_LEN],
+    hash: Hash,
+}
+
+impl HeadHeader {
+    fn new(name: [u8; MAX_HEAD_NAME_LEN], hash: Hash) -> Self {
+        Self {
+            magic_marker: MAGIC_MARKER_HEAD,
+            name,
+            hash,
+        }
+    }
+}
+
+// Names are stored zero padded, so they can't contain zero bytes themselves.
+fn head_name(name: &str) -> Option<[u8; MAX_HEAD_NAME_LEN]> {
+    if name.len() > MAX_HEAD_NAME_LEN || name.contains('\0') {
+        return None;
+    }
+    let mut padded = [0; MAX_HEAD_NAME_LEN];
+    padded[..name.len()].copy_from_slice(name.as_bytes());
+    Some(padded)
+}
+
+#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
+#[repr(C)]
+struct TombstoneHeader {
+    magic_marker: Id,
+    reserved: Id,
+    hash: Hash,
+}
+
+impl TombstoneHeader {
+    fn new(hash: Hash) -> Self {
+        Self {
+            magic_marker: MAGIC_MARKER_TOMBSTONE,
+            reserved: [0; 16],
+            hash,
+        }
+    }
+}
+
+#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
+#[repr(C)]
+struct FooterHeader {
+    magic_marker: Id,
+    length: U64,
+    reserved: [u8; 8],
+    hash: Hash,
+}
+
+impl FooterHeader {
+    fn new(length: u64, hash: Hash) -> Self {
+        Self {
+            magic_marker: MAGIC_MARKER_FOOTER,
+            length: U64::new(length),
+            reserved: [0; 8],
+            hash,
+        }
+    }
+}
+
+// The index sidecar written by `flush_with_index` starts with this header,
+// followed by the blob entries, the branches and the heads.
+#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
+#[repr(C)]
+struct IndexFileHeader {
+    magic_marker: Id,
+    // The length of the pile file the sidecar describes, and the footer record
+    // at its end, whose hash covers every byte before it.
+    file_length: U64,
+    footer_length: U64,
+    footer_hash: Hash,
+    blobs: U64,
+    branches: U64,
+    heads: U64,
+}
+
+#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
+#[repr(C)]
+struct IndexFileEntry {
+    hash: Hash,
+    offset: U64,
+    length: U64,
+    timestamp: U64,
+    checksum: LeU32,
+    flags: u8,
+    reserved: [u8; 3],
+}
+
+// A branch or head in the index sidecar, branch ids and head names have the same size.
+#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
+#[repr(C)]
+struct IndexFileName {
+    name: Id,
+    hash: Hash,
+}
+
+#[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Copy, Clone)]
+#[repr(C)]
+struct BlobHeader {
+    magic_marker: Id,
+    timestamp: U64,
+    length: U64,
+    hash: Hash,
+}
+
+impl BlobHeader {
+    fn new(timestamp: u64, length: u64, hash: Hash) -> Self {
+        Self {
+            magic_marker: MAGIC_MARKER_BLOB,
+            timestamp: U64::new(timestamp),
+            length: U64::new(length),
+            hash,
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    fn compressed(timestamp: u64, length: u64, hash: Hash) -> Self {
+        Self {
+            magic_marker: MAGIC_MARKER_BLOB_ZSTD,
+            ..Self::new(timestamp, length, hash)
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct LoadOptions {
+    max_size: usize,
+    growable: bool,
+    read_only: bool,
+    lenient: bool,
+    truncate_to_valid: bool,
+    #[cfg(feature = "rayon")]
+    parallel: bool,
+    durability: Durability,
+    alignment: usize,
+    validation_cache: Option<usize>,
+    #[cfg(feature = "encrypt")]
+    encryption_key: Option<EncryptionKey>,
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    io_uring: bool,
+    no_mmap: bool,
+}
+
+impl LoadOptions {
+    fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            growable: false,
+            read_only: false,
+            lenient: false,
+            truncate_to_valid: false,
+            #[cfg(feature = "rayon")]
+            parallel: false,
+            durability: Durability::None,
+            alignment: DEFAULT_ALIGNMENT,
+            validation_cache: None,
+            #[cfg(feature = "encrypt")]
+            encryption_key: None,
+            #[cfg(all(feature = "io_uring", target_os = "linux"))]
+            io_uring: false,
+            no_mmap: false,
+        }
+    }
+}
+
+/// Describes what `load_lenient` had to skip to open a pile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Bytes after the last complete record that were ignored.
+    pub ignored_bytes: usize,
+    /// Whether the ignored bytes were cut off the file.
+    pub truncated: bool,
+}
+
+/// Describes what `repair` kept and discarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of intact records before the first invalid one.
+    pub records_kept: usize,
+    /// Bytes that were cut off the file, starting at the first invalid record.
+    pub discarded_bytes: usize,
+}
+
+/// Describes what `merge_from` added to a pile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Blobs that were missing and have been added.
+    pub added: usize,
+    /// Blobs that were already present.
+    pub skipped: usize,
+}
+
+/// Describes what `gc` kept and collected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcReport {
+    /// Blobs reachable from the roots, which have been kept.
+    pub reachable: usize,
+    /// Unreachable blobs that have been dropped.
+    pub collected: usize,
+    /// How much shorter the file got.
+    pub reclaimed_bytes: usize,
+}
+
+/// The header of a stored blob as it is in the file, see `Pile::get_header`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawHeader {
+    /// Identifies the record type, and whether the blob is compressed.
+    pub magic_marker: Id,
+    /// Milliseconds since the unix epoch when the blob was inserted.
+    pub timestamp: u64,
+    /// Length of the stored bytes, which are compressed or encrypted if the blob is.
+    /// The nonce of an encrypted blob follows this header and isn't counted.
+    pub length: u64,
+    pub hash: Hash,
+}
+
+/// Space usage of a pile, see `Pile::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PileStats {
+    /// Length of the file, including removed blobs and non-blob records.
+    pub file_length: usize,
+    /// Number of stored blobs.
+    pub blob_count: usize,
+    /// Sum of the stored blob lengths, compressed blobs count with their compressed length
+    /// and encrypted blobs with their tag.
+    pub total_payload_bytes: usize,
+    /// Bytes spent on padding the stored blobs to the pile's alignment.
+    pub total_padding_bytes: usize,
+    /// Bytes spent on the record headers of the stored blobs.
+    pub header_overhead_bytes: usize,
+    /// Number of blobs passed to the insert methods since the pile was loaded,
+    /// including those that were already stored.
+    pub inserts_total: u64,
+    /// Number of inserted blobs that were already stored and not written again.
+    pub inserts_deduplicated: u64,
+    /// Sum of the stored lengths of the blobs written since the pile was loaded.
+    pub bytes_written: u64,
+    /// Sum of the lengths of the inserted blobs that were already stored.
+    pub bytes_deduplicated: u64,
+}
+
+// Cumulative insert counters for `PileStats`, atomics keep them off the file lock.
+#[derive(Debug, Default)]
+struct InsertCounters {
+    inserts_total: AtomicU64,
+    inserts_deduplicated: AtomicU64,
+    bytes_written: AtomicU64,
+    bytes_deduplicated: AtomicU64,
+}
+
+impl InsertCounters {
+    fn written(&self, length: usize) {
+        self.inserts_total.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(length as u64, Ordering::Relaxed);
+    }
+
+    fn deduplicated(&self, length: usize) {
+        self.inserts_total.fetch_add(1, Ordering::Relaxed);
+        self.inserts_deduplicated.fetch_add(1, Ordering::Relaxed);
+        self.bytes_deduplicated.fetch_add(length as u64, Ordering::Relaxed);
+    }
+}
+
+// A record as read by `read_record`. Blobs are read separately when they are needed,
+// their bytes start right behind the record's header.
+enum Record {
+    Blob {
+        hash: Hash,
+        timestamp: u64,
+        length: usize,
+        compressed: bool,
+        checksum: Option<u32>,
+    },
+    Branch {
+        branch_id: Id,
+        hash: Hash,
+    },
+    Tombstone {
+        hash: Hash,
+    },
+    Footer {
+        length: usize,
+        hash: Hash,
+    },
+    Head {
+        name: [u8; MAX_HEAD_NAME_LEN],
+        hash: Hash,
+    },
+}
+
+// Reads the version record at the start of a file of `file_len` bytes, if there is one.
+// Files without one are version 0 and use the default alignment.
+fn read_version(storage: &Storage, file_len: usize) -> Result<Option<VersionHeader>, LoadError> {
+    if file_len < 64 {
+        return Ok(None);
+    }
+    let mut bytes = storage.read(0, 64)?;
+    if bytes[0..16] != MAGIC_MARKER_VERSION {
+        return Ok(None);
+    }
+    let Ok(header) = bytes.view_prefix::<VersionHeader>() else {
+        return Err(LoadError::HeaderError);
+    };
+    if header.version > FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(header.version));
+    }
+    let alignment = header.alignment();
+    if !alignment.is_power_of_two() {
+        return Err(LoadError::HeaderError);
+    }
+    if header_length(alignment) > file_len {
+        return Err(LoadError::UnexpectedEndOfFile {
+            offset: 0,
+            missing: header_length(alignment) - file_len,
+        });
+    }
+    Ok(Some(*header))
+}
+
+// Reads the record at `offset` from a file with the given format version and alignment,
+// whose records end at `end`. Returns the record and the offset of the next one,
+// or `None` if the record is incomplete. Only the header and checksum of blobs are read.
+fn read_record(
+    storage: &Storage,
+    offset: usize,
+    end: usize,
+    version: u8,
+    alignment: usize,
+) -> Result<Option<(Record, usize)>, LoadError> {
+    let header_length = header_length(alignment);
+    if end - offset < header_length {
+        return Ok(None);
+    }
+    let mut header_bytes = storage.read(offset, 64)?;
+    let mut next = offset + header_length;
+    let magic = header_bytes[0..16].try_into().unwrap();
+    #[cfg(not(feature = "zstd"))]
+    if magic == MAGIC_MARKER_BLOB_ZSTD {
+        return Err(LoadError::CompressionUnsupported);
+    }
+    let record = match magic {
+        MAGIC_MARKER_BLOB | MAGIC_MARKER_BLOB_ZSTD => {
+            let Ok(header) = header_bytes.view_prefix::<BlobHeader>() else {
+                return Err(LoadError::HeaderError);
+            };
+            // A length past the end of the file, which may not even fit into a `usize`
+            // on 32 bit targets, means that the record wasn't written completely.
+            let Some(length) = usize::try_from(header.length.get())
+                .ok()
+                .filter(|&length| length <= end - next)
+            else {
+                return Ok(None);
+            };
+            next += length;
+
+            let padding = padding_for_blob(length, alignment);
+            if padding > end - next {
+                return Ok(None);
+            }
+            let checksum = if version >= 2 && padding >= 4 {
+                let bytes = storage.read(next, 4)?;
+                Some(u32::from_le_bytes(bytes[..].try_into().unwrap()))
+            } else {
+                None
+            };
+            next += padding;
+
+            // Version 0 writers padded aligned blobs with a full zeroed block,
+            // which can never be mistaken for a record header.
+            if version == 0
+                && length.is_multiple_of(64)
+                && end - next >= 64
+                && storage.read(next, 64)?[..] == [0; 64]
+            {
+                next += 64;
+            }
+
+            Record::Blob {
+                hash: header.hash,
+                timestamp: header.timestamp.get(),
+                length,
+                compressed: magic == MAGIC_MARKER_BLOB_ZSTD,
+                checksum,
+            }
+        }
+        MAGIC_MARKER_BRANCH => {
+            let Ok(header) = header_bytes.view_prefix::<BranchHeader>() else {
+                return Err(LoadError::HeaderError);
+            };
+            Record::Branch {
+                branch_id: header.branch_id,
+                hash: header.hash,
+            }
+        }
+        MAGIC_MARKER_TOMBSTONE => {
+            let Ok(header) = header_bytes.view_prefix::<TombstoneHeader>() else {
+                return Err(LoadError::HeaderError);
+            };
+            Record::Tombstone { hash: header.hash }
+        }
+        MAGIC_MARKER_HEAD => {
+            let Ok(header) = header_bytes.view_prefix::<HeadHeader>() else {
+                return Err(LoadError::HeaderError);
+            };
+            Record::Head {
+                name: header.name,
+                hash: header.hash,
+            }
+        }
+        MAGIC_MARKER_FOOTER => {
+            let Ok(header) = header_bytes.view_prefix::<FooterHeader>() else {
+                return Err(LoadError::HeaderError);
+            };
+            Record::Footer {
+                length: header.length.get() as usize,
+                hash: header.hash,
+            }
+        }
+        MAGIC_MARKER_VERSION => return Err(LoadError::HeaderError),
+        _ => return Err(LoadError::MagicMarkerError),
+    };
+    Ok(Some((record, next)))
+}
+
+// The contents of an index sidecar, see `read_index_file`.
+struct IndexFile {
+    records: Vec<(Hash, Option<IndexEntry>)>,
+    branches: HashMap<Id, Hash>,
+    heads: HashMap<[u8; MAX_HEAD_NAME_LEN], Hash>,
+    footer: Option<(usize, Hash)>,
+}
+
+// The index sidecar of the pile at `path`.
+fn index_file_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".idx");
+    path.with_file_name(name)
+}
+
+// Reads the records of the file from the index sidecar at `path`, if there is one
+// that matches the file. Otherwise `load` falls back to scanning the file.
+//
+// The sidecar matches if the file still ends with the footer it was written with.
+// Any other file, e.g. one that was appended to or rewritten, ends differently
+// or has a footer with a different hash.
+fn read_index_file(
+    path: &Path,
+    storage: &Storage,
+    file_len: usize,
+    version: u8,
+    alignment: usize,
+    encrypted: bool,
+) -> Option<IndexFile> {
+    let data = std::fs::read(path).ok()?;
+    let (header, mut rest) = IndexFileHeader::try_ref_from_prefix(&data).ok()?;
+    let footer_offset = file_len.checked_sub(header_length(alignment))?;
+    if header.magic_marker != MAGIC_MARKER_INDEX_FILE
+        || header.file_length.get() != file_len as u64
+        || header.footer_length.get() != footer_offset as u64
+    {
+        return None;
+    }
+    let (record, _) = read_record(storage, footer_offset, file_len, version, alignment).ok()??;
+    let Record::Footer { length, hash } = record else {
+        return None;
+    };
+    if length != footer_offset || hash != header.footer_hash {
+        return None;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..header.blobs.get() {
+        let (entry, tail) = IndexFileEntry::try_ref_from_prefix(rest).ok()?;
+        rest = tail;
+        let offset = usize::try_from(entry.offset.get()).ok()?;
+        let length = usize::try_from(entry.length.get()).ok()?;
+        if offset.checked_add(length)? > file_len {
+            return None;
+        }
+        let blob = IndexEntry::loaded(
+            storage,
+            offset,
+            length,
+            entry.timestamp.get(),
+            entry.flags & INDEX_FILE_COMPRESSED != 0,
+            encrypted,
+            (entry.flags & INDEX_FILE_CHECKSUM != 0).then(|| entry.checksum.get()),
+        )
+        .ok()?;
+        records.push((entry.hash, Some(blob)));
+    }
+    #[cfg(not(feature = "zstd"))]
+    if records.iter().any(|(_, blob)| blob.as_ref().is_some_and(|blob| blob.compressed)) {
+        return None;
+    }
+
+    let mut read_names = |count: u64| {
+        let mut names = HashMap::new();
+        for _ in 0..count {
+            let (entry, tail) = IndexFileName::try_ref_from_prefix(rest).ok()?;
+            rest = tail;
+            names.insert(entry.name, entry.hash);
+        }
+        Some(names)
+    };
+    let branches = read_names(header.branches.get())?;
+    let heads = read_names(header.heads.get())?;
+
+    Some(IndexFile {
+        records,
+        branches,
+        heads,
+        footer: Some((length, hash)),
+    })
+}
+
+// Returns whether the bytes in `range` are all zero.
+fn zeroed(storage: &Storage, range: Range<usize>) -> Result<bool, std::io::Error> {
+    let mut zeroed = true;
+    storage.for_each_chunk(range, |chunk| {
+        zeroed &= chunk.iter().all(|&byte| byte == 0);
+        Ok(())
+    })?;
+    Ok(zeroed)
+}
+
+// Returns how many bytes the incomplete record at `offset` is missing.
+// Only blob headers tell the length of the record, for partial headers
+// only the missing part of the header is known.
+fn missing_bytes(
+    storage: &Storage,
+    offset: usize,
+    end: usize,
+    alignment: usize,
+) -> Result<usize, std::io::Error> {
+    let available = end - offset;
+    let mut record_length = header_length(alignment);
+    let bytes = storage.read(offset, available.min(64))?;
+    if bytes.len() >= 64
+        && (bytes[0..16] == MAGIC_MARKER_BLOB || bytes[0..16] == MAGIC_MARKER_BLOB_ZSTD)
+    {
+        if let Ok((header, _)) = BlobHeader::try_ref_from_prefix(&bytes) {
+            let length = usize::try_from(header.length.get()).unwrap_or(usize::MAX);
+            record_length = record_length
+                .saturating_add(length)
+                .saturating_add(padding_for_blob(length, alignment));
+        }
+    }
+    Ok(record_length.saturating_sub(available))
+}
+
+// A Bloom filter over the stored hashes, sized from the blob count.
+// Bits are only ever set, so it is read and updated through atomics,
+// removed blobs stay in the filter until it is rebuilt from the index.
+struct BloomFilter {
+    words: Vec<AtomicU64>,
+    // The number of blobs the filter was sized for, and how many were inserted.
+    capacity: usize,
+    blobs: AtomicUsize,
+}
+
+impl BloomFilter {
+    // Leaves room for the pile to double in size before the filter is rebuilt.
+    fn with_capacity(blobs: usize) -> Self {
+        let words = (2 * blobs.max(1024) * BLOOM_BITS_PER_BLOB / 64).next_power_of_two();
+        Self {
+            words: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            capacity: words * 64 / BLOOM_BITS_PER_BLOB,
+            blobs: AtomicUsize::new(0),
+        }
+    }
+
+    // The filter only holds hashes, so a shard poisoned by a panicking writer can still be read.
+    fn from_index(index: &Index) -> Self {
+        let bloom = Self::with_capacity(index.len());
+        for shard in index.shards() {
+            for hash in shard.read().unwrap_or_else(PoisonError::into_inner).keys() {
+                bloom.insert(hash);
+            }
+        }
+        bloom
+    }
+
+    // Whether more blobs were inserted than the filter was sized for.
+    fn is_full(&self) -> bool {
+        self.blobs.load(Ordering::Relaxed) > self.capacity
+    }
+
+    // Hashes are uniformly distributed, so their bytes can be used as probe positions directly.
+    fn probes(&self, hash: &Hash) -> impl Iterator<Item = (usize, u64)> {
+        let mask = self.words.len() * 64 - 1;
+        let hash = *hash;
+        (0..BLOOM_PROBES).map(move |probe| {
+            let bytes = hash[probe * 8..(probe + 1) * 8].try_into().unwrap();
+            let bit = u64::from_le_bytes(bytes) as usize & mask;
+            (bit / 64, 1 << (bit % 64))
+        })
+    }
+
+    fn insert(&self, hash: &Hash) {
+        for (word, bit) in self.probes(hash) {
+            self.words[word].fetch_or(bit, Ordering::Relaxed);
+        }
+        self.blobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.probes(hash)
+            .all(|(word, bit)| self.words[word].load(Ordering::Relaxed) & bit != 0)
+    }
+}
+
+// Walks the records from `offset` to `end` and returns how many are intact and
+// where they end, stopping at the first incomplete or malformed one.
+fn intact_records(
+    storage: &Storage,
+    mut offset: usize,
+    end: usize,
+    version: u8,
+    alignment: usize,
+) -> Result<(usize, usize), LoadError> {
+    let mut records = 0;
+    loop {
+        match read_record(storage, offset, end, version, alignment) {
+            Ok(Some((_, next))) => {
+                records += 1;
+                offset = next;
+            }
+            Ok(None) | Err(LoadError::MagicMarkerError | LoadError::HeaderError) => {
+                return Ok((records, offset));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Decrypted blobs up to a total size, by the offset of their record, so that
+// reading a blob again doesn't decrypt it again. Records are never rewritten,
+// so an offset always refers to the same ciphertext. A full cache evicts blobs
+// in no particular order.
+#[cfg(feature = "encrypt")]
+struct PlaintextCache {
+    capacity: usize,
+    size: usize,
+    blobs: HashMap<usize, Bytes>,
+}
+
+#[cfg(feature = "encrypt")]
+impl PlaintextCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            blobs: HashMap::new(),
+        }
+    }
+
+    fn get(&self, offset: usize) -> Option<Bytes> {
+        self.blobs.get(&offset).cloned()
+    }
+
+    fn insert(&mut self, offset: usize, plaintext: Bytes) {
+        if plaintext.len() > self.capacity || self.blobs.contains_key(&offset) {
+            return;
+        }
+        while self.size + plaintext.len() > self.capacity {
+            let evicted = *self.blobs.keys().next().expect("the cache isn't empty");
+            self.size -= self.blobs.remove(&evicted).expect("the key exists").len();
+        }
+        self.size += plaintext.len();
+        self.blobs.insert(offset, plaintext);
+    }
+}
+
+// Bounds how many blobs stay validated, see `Pile::load_with_validation_cache`.
+// Every validation or read of a validated blob takes a new tick and stores it in
+// the entry, which stays validated until `capacity` more ticks were taken.
+// So at most `capacity` blobs are validated at a time, without tracking them anywhere else.
+struct ValidationCache {
+    capacity: u64,
+    tick: AtomicU64,
+}
+
+impl ValidationCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity as u64,
+            tick: AtomicU64::new(0),
+        }
+    }
+
+    fn next_tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn is_fresh(&self, validated_at: u64) -> bool {
+        self.tick.load(Ordering::Relaxed) - validated_at < self.capacity
+    }
+}
+
+type IndexShard = HashMap<Hash, Mutex<IndexEntry>>;
+
+// The index is split into shards by the first byte of the hash,
+// so that inserts only block readers of the same shard. Appending to the file
+// still takes the single file lock, so concurrent inserts are written one at a time.
+struct Index {
+    shards: Vec<RwLock<IndexShard>>,
+}
+
+impl Index {
+    fn new(shards: Vec<IndexShard>) -> Self {
+        Self {
+            shards: shards.into_iter().map(RwLock::new).collect(),
+        }
+    }
+
+    fn shard(&self, hash: &Hash) -> &RwLock<IndexShard> {
+        &self.shards[shard_of(hash)]
+    }
+
+    fn shards(&self) -> &[RwLock<IndexShard>] {
+        &self.shards
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+}
+
+fn shard_of(hash: &Hash) -> usize {
+    hash[0] as usize % INDEX_SHARDS
+}
+
+// Applies a blob record or tombstone to the shard of its hash.
+// A blob that was stored again keeps the entry of its first record.
+fn apply_record(shard: &mut IndexShard, hash: Hash, entry: Option<IndexEntry>) {
+    match entry {
+        Some(entry) => {
+            shard.entry(hash).or_insert_with(|| Mutex::new(entry));
+        }
+        None => {
+            shard.remove(&hash);
+        }
+    }
+}
+
+// The blob records and tombstones of a load, in file order, tombstones have no entry.
+// Serial loads apply them to the shards as they are read, parallel loads collect
+// them first and build the shards on rayon's thread pool.
+struct LoadedRecords {
+    count: usize,
+    shards: Vec<IndexShard>,
+    #[cfg(feature = "rayon")]
+    collected: Option<Vec<(Hash, Option<IndexEntry>)>>,
+}
+
+impl LoadedRecords {
+    #[cfg_attr(not(feature = "rayon"), allow(unused_variables))]
+    fn new(parallel: bool) -> Self {
+        Self {
+            count: 0,
+            shards: (0..INDEX_SHARDS).map(|_| HashMap::new()).collect(),
+            #[cfg(feature = "rayon")]
+            collected: parallel.then(Vec::new),
+        }
+    }
+
+    fn push(&mut self, hash: Hash, entry: Option<IndexEntry>) {
+        self.count += 1;
+        #[cfg(feature = "rayon")]
+        if let Some(collected) = &mut self.collected {
+            collected.push((hash, entry));
+            return;
+        }
+        apply_record(&mut self.shards[shard_of(&hash)], hash, entry);
+    }
+
+    fn into_index(self) -> Index {
+        #[cfg(feature = "rayon")]
+        if let Some(collected) = self.collected {
+            return build_index_parallel(collected);
+        }
+        Index::new(self.shards)
+    }
+}
+
+// Splits the records by shard, keeping them in file order within each shard.
+#[cfg(feature = "rayon")]
+fn records_by_shard(
+    records: Vec<(Hash, Option<IndexEntry>)>,
+) -> Vec<Vec<(Hash, Option<IndexEntry>)>> {
+    let mut shards: Vec<_> = (0..INDEX_SHARDS).map(|_| Vec::new()).collect();
+    for record in records {
+        shards[shard_of(&record.0)].push(record);
+    }
+    shards
+}
+
+#[cfg(feature = "rayon")]
+fn build_shard(records: Vec<(Hash, Option<IndexEntry>)>) -> IndexShard {
+    let mut shard = HashMap::with_capacity(records.len());
+    for (hash, entry) in records {
+        apply_record(&mut shard, hash, entry);
+    }
+    shard
+}
+
+// Shards are independent, so every shard is built on its own thread.
+#[cfg(feature = "rayon")]
+fn build_index_parallel(records: Vec<(Hash, Option<IndexEntry>)>) -> Index {
+    use rayon::prelude::*;
+
+    Index::new(records_by_shard(records).into_par_iter().map(build_shard).collect())
+}
+
+type ValidationHook = Box<dyn Fn(Hash, bool) + Send + Sync>;
+
+/// A blob store in a single append-only file.
+///
+/// Blobs are addressed by their `H` digest, which defaults to Blake3.
+///
+/// `MAX_PILE_SIZE` is the default size limit used by `load`,
+/// `load_with_capacity` allows choosing the limit at runtime instead.
+///
+/// The file is read through a memory mapping that is replaced by a larger one
+/// whenever an insert grows the file past it. Every mapping is reference counted
+/// and all `Bytes` handed out hold on to the mapping they were created from,
+/// so a replaced mapping is only unmapped once nothing points into it anymore.
+/// Because the file is append-only, the contents of an old mapping never change.
+/// This also keeps returned `Bytes` valid after the pile is dropped,
+/// the file is closed right away but the mapping lives until the last `Bytes` is gone.
+///
+/// Piles are `Send` and `Sync` whenever `H` is, through the auto traits and without
+/// an `unsafe impl`: all mutable state sits behind locks or atomics, and `Bytes` only
+/// borrow ranges of the mapping that were completely written and are never written again.
+/// Share a pile between threads with an `Arc`, inserts and reads both take `&self`.
+/// Blobs are hashed before taking any lock, but the records are appended one insert
+/// at a time under a single file lock, so concurrent inserts don't write in parallel.
+pub struct Pile<const MAX_PILE_SIZE: usize, H = Blake3> {
+    file: Mutex<AppendFile>,
+    // Another handle of the file that `flush` syncs without blocking appends,
+    // its lock keeps flushes from overlapping each other.
+    sync_file: Mutex<File>,
+    // Unknown for piles created from an open file.
+    path: Option<PathBuf>,
+    storage: Storage,
+    max_size: usize,
+    read_only: bool,
+    version: u8,
+    alignment: usize,
+    durability: Durability,
+    // What the pile was loaded with, compacted and truncated copies are loaded the same way.
+    options: LoadOptions,
+    access_pattern: Mutex<AccessPattern>,
+    bloom: RwLock<BloomFilter>,
+    validation_hook: Option<ValidationHook>,
+    validation_cache: Option<ValidationCache>,
+    subscribers: Mutex<Vec<Sender<Hash>>>,
+    insert_counters: InsertCounters,
+    // The covered length and hash of the last footer,
+    // and the hash state of the file up to some length for writing the next one.
+    footer: Mutex<Option<(usize, Hash)>>,
+    footer_hasher: Mutex<Option<(usize, H)>>,
+    index: Index,
+    branches: RwLock<HashMap<Id, Hash>>,
+    heads: RwLock<HashMap<[u8; MAX_HEAD_NAME_LEN], Hash>>,
+    #[cfg(feature = "encrypt")]
+    encryption_key: Option<EncryptionKey>,
+    #[cfg(feature = "encrypt")]
+    plaintext_cache: Mutex<PlaintextCache>,
+    _hasher: PhantomData<H>,
+}
+
+/// A pile whose blobs are appended by a background thread, see `Pile::with_writer_thread`.
+pub struct QueuedPile<const MAX_PILE_SIZE: usize, H = Blake3> {
+    pile: Arc<Pile<MAX_PILE_SIZE, H>>,
+    sender: Option<Sender<(Hash, Bytes)>>,
+    writer: Option<JoinHandle<Result<(), InsertError>>>,
+}
+
+/// Collects blobs and writes them to a pile with a single append.
+///
+/// Collect an iterator of `Bytes` into a builder to get a pile from it,
+/// `FromIterator` can't do that directly because it can't fail or take a path.
+#[derive(Debug, Default)]
+pub struct PileBuilder {
+    blobs: Vec<Bytes>,
+}
+
+impl PileBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, blob: Bytes) {
+        self.blobs.push(blob);
+    }
+
+    /// Opens the pile at `path` like `Pile::load`, creating it if needed,
+    /// and inserts all collected blobs with `Pile::insert_many`.
+    pub fn build<const MAX_PILE_SIZE: usize, H>(
+        self,
+        path: &Path,
+    ) -> Result<Pile<MAX_PILE_SIZE, H>, LoadError>
+    where
+        H: Digest<OutputSize = U32>,
+    {
+        let pile = Pile::load(path)?;
+        pile.insert_many(&self.blobs)?;
+        Ok(pile)
+    }
+}
+
+impl FromIterator<Bytes> for PileBuilder {
+    fn from_iter<T: IntoIterator<Item = Bytes>>(iter: T) -> Self {
+        Self {
+            blobs: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<Bytes> for PileBuilder {
+    fn extend<T: IntoIterator<Item = Bytes>>(&mut self, iter: T) {
+        self.blobs.extend(iter);
+    }
+}
+
+/// Spreads blobs over numbered pile files in a directory,
+/// to store more than fits into a single pile.
+///
+/// Blobs are inserted into the last pile, once that is full the next file is started.
+/// Members are numbered by their position, the files are named `0.pile`, `1.pile` and so on.
+pub struct PileSet<const MAX_PILE_SIZE: usize, H = Blake3> {
+    dir: PathBuf,
+    piles: RwLock<Vec<Pile<MAX_PILE_SIZE, H>>>,
+}
+
+impl<const MAX_PILE_SIZE: usize, H> PileSet<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32>,
+{
+    /// Opens the piles in `dir`, starting with `0.pile`, and creates `0.pile`
+    /// if there are none. Every pile is locked like by `Pile::load`.
+    ///
+    /// The members have to be numbered without gaps, if one is missing but a later
+    /// one exists this fails with `MissingMember`, instead of ignoring the later ones.
+    pub fn open(dir: &Path) -> Result<Self, LoadError> {
+        let mut piles = Vec::new();
+        while Self::member_path(dir, piles.len()).exists() {
+            piles.push(Pile::open_existing(&Self::member_path(dir, piles.len()))?);
+        }
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let member = name.to_str().and_then(|name| name.strip_suffix(".pile"));
+            if member.and_then(|member| member.parse::<usize>().ok()) > Some(piles.len()) {
+                return Err(LoadError::MissingMember(piles.len()));
+            }
+        }
+        if piles.is_empty() {
+            piles.push(Pile::load(&Self::member_path(dir, 0))?);
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            piles: RwLock::new(piles),
+        })
+    }
+
+    fn member_path(dir: &Path, member: usize) -> PathBuf {
+        dir.join(format!("{member}.pile"))
+    }
+
+    /// Stores a blob and returns its hash and the member it is stored in.
+    ///
+    /// Blobs that are already stored in any member aren't stored again.
+    /// If the next pile can't be created once the last one is full,
+    /// this fails with `RolloverFailed`.
+    pub fn insert(&self, value: &Bytes) -> Result<(Hash, usize), InsertError> {
+        let hash: Hash = H::digest(value).into();
+        loop {
+            let piles = self.piles.read()?;
+            if let Some(member) = piles.iter().position(|pile| pile.contains(&hash)) {
+                return Ok((hash, member));
+            }
+            let active = piles.len() - 1;
+            match piles[active].insert_blob_validated(hash, value) {
+                Ok(_) => return Ok((hash, active)),
+                // A blob that doesn't fit into an empty pile never will.
+                Err(InsertError::PileTooLarge) if !piles[active].is_empty() => {}
+                Err(err) => return Err(err),
+            }
+            drop(piles);
+
+            // Another thread may have started the next pile in the meantime.
+            let mut piles = self.piles.write()?;
+            if piles.len() == active + 1 {
+                let next = Pile::load(&Self::member_path(&self.dir, active + 1))
+                    .map_err(|err| InsertError::RolloverFailed(Box::new(err)))?;
+                piles.push(next);
+            }
+        }
+    }
+
+    /// Looks up a blob in all members, validating it on first access like `Pile::get_blob`.
+    pub fn get(&self, hash: &Hash) -> Result<Option<Bytes>, GetError> {
+        for pile in self.piles.read()?.iter() {
+            if let Some(bytes) = pile.get_blob(hash)? {
+                return Ok(Some(bytes));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.piles.read().unwrap().iter().any(|pile| pile.contains(hash))
+    }
+
+    /// The number of blobs in all members.
+    pub fn len(&self) -> usize {
+        self.piles.read().unwrap().iter().map(Pile::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of pile files.
+    pub fn member_count(&self) -> usize {
+        self.piles.read().unwrap().len()
+    }
+
+    /// Syncs all members to disk, see `Pile::flush`.
+    pub fn flush(&self) -> Result<(), FlushError> {
+        for pile in self.piles.read()?.iter() {
+            pile.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A hash together with the pile it was looked up in, see `Pile::get`.
+///
+/// This keeps hashes of different piles from being mixed up,
+/// and borrows the pile, so the handle can't outlive it.
+pub struct BlobRef<'a, const MAX_PILE_SIZE: usize, H = Blake3> {
+    pile: &'a Pile<MAX_PILE_SIZE, H>,
+    hash: Hash,
+}
+
+impl<const MAX_PILE_SIZE: usize, H> Clone for BlobRef<'_, MAX_PILE_SIZE, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<const MAX_PILE_SIZE: usize, H> Copy for BlobRef<'_, MAX_PILE_SIZE, H> {}
+
+impl<const MAX_PILE_SIZE: usize, H> fmt::Debug for BlobRef<'_, MAX_PILE_SIZE, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlobRef({})", HexHash(&self.hash))
+    }
+}
+
+impl<'a, const MAX_PILE_SIZE: usize, H> BlobRef<'a, MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32>,
+{
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    pub fn pile(&self) -> &'a Pile<MAX_PILE_SIZE, H> {
+        self.pile
+    }
+
+    /// Returns the blob like `Pile::get_blob`, or `NotFound` if it was removed since.
+    pub fn fetch(&self) -> Result<Bytes, GetError> {
+        self.pile.get_blob(&self.hash)?.ok_or(GetError::NotFound)
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    IoError(std::io::Error),
+    MagicMarkerError,
+    HeaderError,
+    /// The file ends within the record starting at `offset`,
+    /// which is `missing` bytes longer than what is left of the file.
+    UnexpectedEndOfFile {
+        offset: usize,
+        missing: usize,
+    },
+    /// The file ends with a partial record, e.g. after a torn write.
+    /// `load_lenient` can ignore the trailing bytes.
+    FileLengthError {
+        file_length: usize,
+        alignment: usize,
+    },
+    PileTooLarge,
+    /// The file is already longer than the size limit the pile was opened with,
+    /// it needs to be opened with a limit of at least `file_len`.
+    CapacityTooSmall {
+        file_len: usize,
+        max: usize,
+    },
+    /// The size limit is not a multiple of the pile's record alignment.
+    MisalignedCapacity {
+        max: usize,
+        alignment: usize,
+    },
+    InsertError(InsertError),
+    CompressionUnsupported,
+    UnsupportedVersion(u8),
+    /// The pile was created from an open file, so its path isn't known.
+    PathUnknown,
+    /// The requested record alignment is not a power of two.
+    InvalidAlignment(usize),
+    /// The file contains no record of the blob, see `Pile::truncate_to`.
+    NotFound,
+    /// Another writer has the file open.
+    AlreadyLocked,
+    EncryptionUnsupported,
+    /// The pile is encrypted with a different key, or the key and the pile
+    /// disagree on whether it is encrypted at all.
+    KeyMismatch,
+    /// The blobs with these hashes don't match them, see `Pile::load_verified`.
+    ValidationFailed(Vec<Hash>),
+    /// The sample rate of `Pile::load_sampled` is NaN or infinite.
+    InvalidSampleRate(f64),
+    /// The member with this number is missing from a `PileSet`, but later ones exist.
+    MissingMember(usize),
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl From<InsertError> for LoadError {
+    fn from(err: InsertError) -> Self {
+        Self::InsertError(err)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError(err) => write!(f, "failed to load pile: {err}"),
+            Self::MagicMarkerError => write!(f, "header magic marker mismatch"),
+            Self::HeaderError => write!(f, "malformed record header"),
+            Self::UnexpectedEndOfFile { offset, missing } => write!(
+                f,
+                "unexpected end of file, the record at offset {offset} is missing {missing} bytes"
+            ),
+            Self::FileLengthError {
+                file_length,
+                alignment,
+            } => write!(
+                f,
+                "file length {file_length} is not a multiple of {alignment}, \
+                 the last {} bytes are not a complete record",
+                file_length % alignment
+            ),
+            Self::PileTooLarge => write!(f, "pile exceeds its maximum size"),
+            Self::CapacityTooSmall { file_len, max } => write!(
+                f,
+                "file has {file_len} bytes, more than the maximum pile size of {max} bytes"
+            ),
+            Self::MisalignedCapacity { max, alignment } => write!(
+                f,
+                "maximum pile size of {max} bytes is not a multiple of the record alignment {alignment}"
+            ),
+            Self::InsertError(err) => write!(f, "failed to write pile: {err}"),
+            Self::CompressionUnsupported => {
+                write!(f, "pile contains compressed blobs, but the zstd feature is disabled")
+            }
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "pile has format version {version}, only versions up to {FORMAT_VERSION} are supported"
+            ),
+            Self::PathUnknown => write!(f, "pile was opened from a file, its path is unknown"),
+            Self::NotFound => write!(f, "blob not found in the pile"),
+            Self::AlreadyLocked => write!(f, "pile is locked by another process"),
+            Self::InvalidAlignment(alignment) => {
+                write!(f, "record alignment {alignment} is not a power of two")
+            }
+            Self::EncryptionUnsupported => {
+                write!(f, "pile is encrypted, but the encrypt feature is disabled")
+            }
+            Self::KeyMismatch => write!(f, "encryption key doesn't match the pile"),
+            Self::ValidationFailed(invalid) => {
+                write!(f, "{} blobs don't match their hash", invalid.len())
+            }
+            Self::InvalidSampleRate(rate) => write!(f, "sample rate {rate} is not a finite number"),
+            Self::MissingMember(member) => {
+                write!(f, "pile set is missing {member}.pile, but has later members")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(err) => Some(err),
+            Self::InsertError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InsertError {
+    IoError(std::io::Error),
+    PoisonError,
+    PileTooLarge,
+    ClockError(SystemTimeError),
+    ReadOnly,
+    InvalidHeadName,
+    /// The blob is larger than `max`, the largest blob that fits into an empty pile,
+    /// so it can't be stored in this pile at all.
+    BlobTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The writer thread of a `QueuedPile` stopped after an error,
+    /// `QueuedPile::shutdown` returns that error.
+    WriterStopped,
+    /// `Pile::import_lengthprefixed` stopped at `error`,
+    /// after storing the blobs with the `imported` hashes.
+    ImportInterrupted {
+        imported: Vec<Hash>,
+        error: Box<InsertError>,
+    },
+    /// A `PileSet` couldn't create the next pile after the last one filled up.
+    RolloverFailed(Box<LoadError>),
+    /// The pile has an older format version than head records, see `FORMAT_VERSION`.
+    UnsupportedVersion(u8),
+}
+
+impl From<std::io::Error> for InsertError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl<T> From<PoisonError<T>> for InsertError {
+    fn from(_err: PoisonError<T>) -> Self {
+        Self::PoisonError
+    }
+}
+
+impl From<SystemTimeError> for InsertError {
+    fn from(err: SystemTimeError) -> Self {
+        Self::ClockError(err)
+    }
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError(err) => write!(f, "failed to append to pile: {err}"),
+            Self::PoisonError => write!(f, "pile lock poisoned"),
+            Self::PileTooLarge => write!(f, "insert would exceed the maximum pile size"),
+            Self::ClockError(err) => write!(f, "system clock is before the unix epoch: {err}"),
+            Self::ReadOnly => write!(f, "pile was opened read-only"),
+            Self::InvalidHeadName => write!(
+                f,
+                "head names must be at most {MAX_HEAD_NAME_LEN} bytes long and not contain zero bytes"
+            ),
+            Self::BlobTooLarge { size, max } => write!(
+                f,
+                "blob of {size} bytes can never fit into the pile, the largest possible blob has {max} bytes"
+            ),
+            Self::WriterStopped => write!(f, "the writer thread of the pile stopped after an error"),
+            Self::ImportInterrupted { imported, error } => {
+                write!(f, "import stopped after {} blobs: {error}", imported.len())
+            }
+            Self::RolloverFailed(err) => write!(f, "failed to start the next pile: {err}"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "pile has format version {version}, heads need version {FOOTER_AND_HEAD_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InsertError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(err) => Some(err),
+            Self::ClockError(err) => Some(err),
+            Self::ImportInterrupted { error, .. } => Some(error.as_ref()),
+            Self::RolloverFailed(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GetError {
+    PoisonError,
+    ValidationError {
+        expected: Hash,
+        actual: Hash,
+        bytes: Bytes,
+    },
+    OutOfRange {
+        range: Range<usize>,
+        length: usize,
+    },
+    /// The blob of a `BlobRef` was removed from its pile.
+    NotFound,
+    InsertError(InsertError),
+}
+
+impl From<InsertError> for GetError {
+    fn from(err: InsertError) -> Self {
+        Self::InsertError(err)
+    }
+}
+
+impl<T> From<PoisonError<T>> for GetError {
+    fn from(_err: PoisonError<T>) -> Self {
+        Self::PoisonError
+    }
+}
+
+impl fmt::Display for GetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PoisonError => write!(f, "pile lock poisoned"),
+            Self::ValidationError {
+                expected, actual, ..
+            } => write!(
+                f,
+                "blob failed validation: expected hash {}, found {}",
+                HexHash(expected),
+                HexHash(actual)
+            ),
+            Self::OutOfRange { range, length } => write!(
+                f,
+                "range {}..{} is out of bounds for a blob of {length} bytes",
+                range.start, range.end
+            ),
+            Self::NotFound => write!(f, "blob not found"),
+            Self::InsertError(err) => write!(f, "failed to store blob: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for GetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InsertError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FlushError {
+    IoError(std::io::Error),
+    PoisonError,
+    ReadOnly,
+    PileTooLarge,
+    /// The pile was created from an open file, so there is no path for its index.
+    PathUnknown,
+    /// The pile has an older format version than footer records, see `FORMAT_VERSION`.
+    UnsupportedVersion(u8),
+}
+
+impl From<std::io::Error> for FlushError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl<T> From<PoisonError<T>> for FlushError {
+    fn from(_err: PoisonError<T>) -> Self {
+        Self::PoisonError
+    }
+}
+
+impl fmt::Display for FlushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError(err) => write!(f, "failed to flush pile: {err}"),
+            Self::PoisonError => write!(f, "pile lock poisoned"),
+            Self::ReadOnly => write!(f, "pile was opened read-only"),
+            Self::PileTooLarge => write!(f, "footer would exceed the maximum pile size"),
+            Self::PathUnknown => write!(f, "pile was opened from a file, its path is unknown"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "pile has format version {version}, footers need version {FOOTER_AND_HEAD_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FlushError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+//TODO Add the ability to skip corrupted blobs
+impl<const MAX_PILE_SIZE: usize, H> Pile<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32>,
+{
+    /// Opens the pile at `path`, creating the file if it doesn't exist.
+    ///
+    /// The file is locked exclusively until the pile is dropped, opening it for
+    /// writing again, from this or another process, fails with `AlreadyLocked`.
+    /// The lock is advisory, it only keeps out other writing piles.
+    pub fn load(path: &Path) -> Result<Self, LoadError> {
+        Self::load_path(path, LoadOptions::new(MAX_PILE_SIZE)).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but fails with a `NotFound` `IoError` instead of
+    /// creating the file if it doesn't exist.
+    pub fn open_existing(path: &Path) -> Result<Self, LoadError> {
+        let file = OpenOptions::new().read(true).append(true).open(path)?;
+        Self::load_file(file, Some(path), LoadOptions::new(MAX_PILE_SIZE)).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but fails with an `AlreadyExists` `IoError` instead of
+    /// opening the file if it exists.
+    pub fn create_new(path: &Path) -> Result<Self, LoadError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(path)?;
+        Self::load_file(file, Some(path), LoadOptions::new(MAX_PILE_SIZE)).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but limits the pile to `max_size` bytes instead of `MAX_PILE_SIZE`.
+    pub fn load_with_capacity(path: &Path, max_size: usize) -> Result<Self, LoadError> {
+        Self::load_path(path, LoadOptions::new(max_size)).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but starts every record at a multiple of `alignment` bytes,
+    /// which has to be a power of two, so that blob bytes are aligned to it as well.
+    ///
+    /// The alignment is stored in the file and only applies to new files,
+    /// existing piles keep the alignment they were created with, see `alignment`.
+    /// Alignments above 64 pad every record header to the alignment.
+    pub fn load_with_alignment(path: &Path, alignment: usize) -> Result<Self, LoadError> {
+        let options = LoadOptions {
+            alignment,
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_path(path, options).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but only remembers that a blob is valid for the `capacity`
+    /// most recently read blobs, older ones are validated again on their next read.
+    ///
+    /// Blobs otherwise stay trusted for as long as the pile is open once they
+    /// were checked, this caps how many are, for huge piles that are read for
+    /// a long time. Blobs inserted through this pile count as recently read.
+    /// The validation state is kept in the index, so the cache takes no extra memory.
+    pub fn load_with_validation_cache(path: &Path, capacity: usize) -> Result<Self, LoadError> {
+        let options = LoadOptions {
+            validation_cache: Some(capacity),
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_path(path, options).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but syncs appended records according to `durability`.
+    pub fn load_with_durability(path: &Path, durability: Durability) -> Result<Self, LoadError> {
+        let options = LoadOptions {
+            durability,
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_path(path, options).map(|(pile, _)| pile)
+    }
+
+    /// Opens an existing pile without write access, all writes to it will fail.
+    ///
+    /// Unlike `load` this never creates the file. Read-only piles don't lock the file,
+    /// so they can follow a writer that has it open, see `rebuild_index`.
+    pub fn open_read_only(path: &Path) -> Result<Self, LoadError> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let options = LoadOptions {
+            read_only: true,
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_file(file, Some(path), options).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but only maps as much of the file as needed
+    /// and grows the mapping by doubling it on demand, up to `MAX_PILE_SIZE`.
+    pub fn load_growable(path: &Path) -> Result<Self, LoadError> {
+        let options = LoadOptions {
+            growable: true,
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_path(path, options).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but tolerates a partially written record or a tail of zeroes at
+    /// the end of the file, e.g. after a crash during an insert, and reports how many
+    /// bytes were ignored.
+    ///
+    /// With `truncate_to_valid` the ignored bytes are cut off the file.
+    /// Otherwise the pile is opened read-only, because new records
+    /// would end up behind the ignored bytes.
+    pub fn load_lenient(
+        path: &Path,
+        truncate_to_valid: bool,
+    ) -> Result<(Self, LoadReport), LoadError> {
+        let options = LoadOptions {
+            lenient: true,
+            truncate_to_valid,
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_path(path, options)
+    }
+
+    /// Like `load`, but builds the index on rayon's thread pool,
+    /// which is faster for piles with many blobs.
+    #[cfg(feature = "rayon")]
+    pub fn load_parallel(path: &Path) -> Result<Self, LoadError> {
+        let options = LoadOptions {
+            parallel: true,
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_path(path, options).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but encrypts blobs with `key` before writing them
+    /// and decrypts them when they are read.
+    ///
+    /// Blobs are still addressed by the hash of their plaintext, so inserting
+    /// the same blob twice stores it once. A new file is marked as encrypted,
+    /// opening an encrypted pile without its key, or a plaintext pile with one,
+    /// fails with `KeyMismatch`. Only blob contents are encrypted, hashes,
+    /// timestamps, lengths, branches and heads are stored in the clear.
+    ///
+    /// Every blob has a random nonce, which is stored in its record header,
+    /// so encrypted piles use an alignment of at least 128 bytes. Blobs stay
+    /// encrypted in memory, reads decrypt them again unless they hit a cache
+    /// of recently decrypted blobs, which holds up to 64 MiB of plaintext.
+    #[cfg(feature = "encrypt")]
+    pub fn load_encrypted(path: &Path, key: EncryptionKey) -> Result<Self, LoadError> {
+        let options = LoadOptions {
+            encryption_key: Some(key),
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_path(path, options).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but submits the writes of an insert through io_uring,
+    /// which saves syscalls when inserting many small blobs.
+    ///
+    /// The file format is the same. Falls back to regular writes on other
+    /// platforms, or if the kernel doesn't support io_uring.
+    /// Each insert submits the header, blob and padding of its record together
+    /// and waits for them to complete, writes aren't batched across inserts.
+    /// Like regular writes, completed writes are only durable after a `flush`
+    /// or a sync of the `Durability` policy, the ring doesn't track durability.
+    #[cfg(feature = "io_uring")]
+    pub fn load_with_io_uring(path: &Path) -> Result<Self, LoadError> {
+        let options = LoadOptions {
+            #[cfg(target_os = "linux")]
+            io_uring: true,
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_path(path, options).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but reads the file with positioned reads instead of mapping it,
+    /// for file systems where memory mapping is unreliable, e.g. some network file systems.
+    ///
+    /// Only record headers are read to build the index. Blobs are read from the file on
+    /// their first access and stay in memory afterwards, as owned copies that aren't
+    /// aligned to the pile's alignment. Reads that fail are logged, and the blob is
+    /// treated as invalid until the pile is loaded again.
+    pub fn load_no_mmap(path: &Path) -> Result<Self, LoadError> {
+        let options = LoadOptions {
+            no_mmap: true,
+            ..LoadOptions::new(MAX_PILE_SIZE)
+        };
+        Self::load_path(path, options).map(|(pile, _)| pile)
+    }
+
+    /// Like `load`, but checks every blob against its hash before returning,
+    /// and fails with `ValidationFailed` listing the blobs that don't match.
+    ///
+    /// This reads the whole file, `load` only validates blobs when they are first read.
+    pub fn load_verified(path: &Path) -> Result<Self, LoadError> {
+        let pile = Self::load(path)?;
+        // Nothing else has seen the pile yet, so none of its locks can be poisoned.
+        let invalid = pile.validate_all().expect("new pile has no poisoned locks");
+        if !invalid.is_empty() {
+            return Err(LoadError::ValidationFailed(invalid));
+        }
+        Ok(pile)
+    }
+
+    /// Like `load`, but checks a random sample of the blobs against their hashes,
+    /// each blob with probability `rate`, and fails with `ValidationFailed` listing
+    /// the sampled blobs that don't match.
+    ///
+    /// This catches widespread corruption at a fraction of the cost of `load_verified`.
+    /// The same `seed` samples the same blobs of the same pile. Rates outside of
+    /// `0.0..=1.0` are clamped to it, a NaN or infinite rate fails with `InvalidSampleRate`.
+    pub fn load_sampled(path: &Path, rate: f64, seed: u64) -> Result<Self, LoadError> {
+        use rand::{Rng, SeedableRng};
+
+        if !rate.is_finite() {
+            return Err(LoadError::InvalidSampleRate(rate));
+        }
+        let pile = Self::load(path)?;
+        let mut hashes: Vec<Hash> = pile.hashes().collect();
+        // The index iterates in a random order, sorting makes the sample depend on the seed only.
+        hashes.sort_unstable();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let rate = rate.clamp(0.0, 1.0);
+        let mut invalid = Vec::new();
+        for hash in hashes {
+            if rng.gen_bool(rate) {
+                // Nothing else has seen the pile yet, so none of its locks can be poisoned.
+                if !pile.validate(&hash).expect("new pile has no poisoned locks") {
+                    invalid.push(hash);
+                }
+            }
+        }
+        if !invalid.is_empty() {
+            return Err(LoadError::ValidationFailed(invalid));
+        }
+        Ok(pile)
+    }
+
+    /// Like `load_with_capacity`, but uses an already opened file instead of a path.
+    ///
+    /// The file needs to be readable, and writable unless the pile is only read.
+    /// Records are appended at the end, regardless of the current file position.
+    /// `max_size` is the size limit of the pile, not the length of its contents,
+    /// which is taken from the file's metadata like for a path.
+    pub fn from_file(mut file: File, max_size: usize) -> Result<Self, LoadError> {
+        file.seek(SeekFrom::End(0))?;
+        Self::load_file(file, None, LoadOptions::new(max_size)).map(|(pile, _)| pile)
+    }
+
+    fn load_path(path: &Path, options: LoadOptions) -> Result<(Self, LoadReport), LoadError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Self::load_file(file, Some(path), options)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(records = tracing::field::Empty, bytes_scanned = tracing::field::Empty)
+        )
+    )]
+    fn load_file(
+        file: File,
+        path: Option<&Path>,
+        options: LoadOptions,
+    ) -> Result<(Self, LoadReport), LoadError> {
+        let max_size = options.max_size;
+        if !options.alignment.is_power_of_two() || options.alignment > u32::MAX as usize {
+            return Err(LoadError::InvalidAlignment(options.alignment));
+        }
+        // Writers interleaving their appends would corrupt the file, so they
+        // lock it exclusively. Readers only see complete records, so they don't lock it.
+        // The lock is released when the file is closed.
+        if !options.read_only {
+            match fs2::FileExt::try_lock_exclusive(&file) {
+                Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+                    return Err(LoadError::AlreadyLocked);
+                }
+                locked => locked?,
+            }
+        }
+        let file_len = file.metadata()?.len() as usize;
+        if file_len > max_size {
+            return Err(LoadError::CapacityTooSmall {
+                file_len,
+                max: max_size,
+            });
+        }
+        let mapping_len = if options.growable {
+            file_len
+                .max(MIN_GROWABLE_MAPPING)
+                .max(header_length(options.alignment))
+                .next_power_of_two()
+                .min(max_size)
+        } else {
+            max_size
+        };
+        let storage = if options.no_mmap {
+            Storage::Read(file.try_clone()?)
+        } else {
+            let mmap = MmapOptions::new()
+                .len(mapping_len)
+                .map_raw_read_only(&file)?;
+            // Hints are only an optimization, failing to apply them is harmless.
+            let _ = advise_mapping(&mmap, AccessPattern::Sequential);
+            Storage::Mapped(RwLock::new(Arc::new(mmap)))
+        };
+        let mut version = 0;
+        let mut encrypted = false;
+        // New files get the requested alignment, existing ones keep theirs.
+        let mut alignment = if file_len == 0 {
+            options.alignment
+        } else {
+            DEFAULT_ALIGNMENT
+        };
+        #[cfg(feature = "encrypt")]
+        if file_len == 0 && options.encryption_key.is_some() {
+            alignment = alignment.max(ENCRYPTED_ALIGNMENT);
+        }
+        let mut start = 0;
+        if let Some(header) = read_version(&storage, file_len)? {
+            version = header.version;
+            alignment = header.alignment();
+            encrypted = header.flags & VERSION_FLAG_ENCRYPTED != 0;
+            start = header_length(alignment);
+            #[cfg(not(feature = "encrypt"))]
+            if encrypted {
+                return Err(LoadError::EncryptionUnsupported);
+            }
+            #[cfg(feature = "encrypt")]
+            match options.encryption_key {
+                Some(key) if !encrypted || header.key_check != key_check(&key) => {
+                    return Err(LoadError::KeyMismatch);
+                }
+                None if encrypted => return Err(LoadError::KeyMismatch),
+                _ => {}
+            }
+            // Encrypted piles before version 5 stored the nonce in front of the ciphertext.
+            #[cfg(feature = "encrypt")]
+            if encrypted && (version < 5 || alignment < ENCRYPTED_ALIGNMENT) {
+                return Err(LoadError::HeaderError);
+            }
+        }
+        // Files without a version record predate encryption.
+        #[cfg(feature = "encrypt")]
+        if version == 0 && file_len > 0 && options.encryption_key.is_some() {
+            return Err(LoadError::KeyMismatch);
+        }
+        // Records are multiples of the alignment, so the last bytes of
+        // a misaligned limit could never be used. The limit doesn't need to be
+        // a multiple of the page size: mappings of any length are valid, the kernel
+        // rounds them up to whole pages, and appends never pass the limit.
+        if !max_size.is_multiple_of(alignment) {
+            return Err(LoadError::MisalignedCapacity {
+                max: max_size,
+                alignment,
+            });
+        }
+        if !options.lenient && !file_len.is_multiple_of(alignment) {
+            return Err(LoadError::FileLengthError {
+                file_length: file_len,
+                alignment,
+            });
+        }
+
+        #[cfg(feature = "rayon")]
+        let mut records = LoadedRecords::new(options.parallel);
+        #[cfg(not(feature = "rayon"))]
+        let mut records = LoadedRecords::new(false);
+        let mut branches = HashMap::new();
+        let mut footer = None;
+        let mut heads = HashMap::new();
+
+        let mut valid_length = file_len;
+        let index_file = path
+            .filter(|_| !options.lenient)
+            .and_then(|path| {
+                let path = index_file_path(path);
+                read_index_file(&path, &storage, file_len, version, alignment, encrypted)
+            });
+        let incomplete = match index_file {
+            Some(index_file) => {
+                for (hash, entry) in index_file.records {
+                    records.push(hash, entry);
+                }
+                branches = index_file.branches;
+                heads = index_file.heads;
+                footer = index_file.footer;
+                false
+            }
+            None => loop {
+                valid_length = start;
+                if start == file_len {
+                    break false;
+                }
+                let (record, next) =
+                    match read_record(&storage, start, file_len, version, alignment) {
+                        Ok(Some(record)) => record,
+                        Ok(None) => break true,
+                        // A crash can leave the file longer than its records, with zeroes
+                        // where the last headers should be, that's an incomplete tail too.
+                        Err(LoadError::MagicMarkerError)
+                            if options.lenient && zeroed(&storage, start..file_len)? =>
+                        {
+                            break true;
+                        }
+                        Err(err) => return Err(err),
+                    };
+                match record {
+                    Record::Blob {
+                        hash,
+                        timestamp,
+                        length,
+                        compressed,
+                        checksum,
+                    } => {
+                        let blob = IndexEntry::loaded(
+                            &storage,
+                            start + header_length(alignment),
+                            length,
+                            timestamp,
+                            compressed,
+                            encrypted,
+                            checksum,
+                        )?;
+                        records.push(hash, Some(blob));
+                    }
+                    Record::Branch { branch_id, hash } => {
+                        branches.insert(branch_id, hash);
+                    }
+                    Record::Tombstone { hash } => {
+                        records.push(hash, None);
+                    }
+                    Record::Footer { length, hash } => {
+                        footer = Some((length, hash));
+                    }
+                    Record::Head { name, hash } => {
+                        heads.insert(name, hash);
+                    }
+                };
+                start = next;
+            },
+        };
+
+        #[cfg(feature = "tracing")]
+        tracing::Span::current()
+            .record("records", records.count)
+            .record("bytes_scanned", valid_length);
+
+        if incomplete && !options.lenient {
+            return Err(LoadError::UnexpectedEndOfFile {
+                offset: valid_length,
+                missing: missing_bytes(&storage, valid_length, file_len, alignment)?,
+            });
+        }
+
+        let index = records.into_index();
+
+        let bloom = RwLock::new(BloomFilter::from_index(&index));
+
+        let mut report = LoadReport {
+            ignored_bytes: file_len - valid_length,
+            truncated: false,
+        };
+        let mut read_only = options.read_only;
+        let mut file_len = file_len;
+        if report.ignored_bytes > 0 {
+            if options.truncate_to_valid {
+                file.set_len(valid_length as u64)?;
+                file_len = valid_length;
+                report.truncated = true;
+            } else {
+                read_only = true;
+            }
+        }
+
+        if file_len == 0 && !read_only {
+            let mut file = &file;
+            #[cfg(feature = "encrypt")]
+            let mut header = match &options.encryption_key {
+                Some(key) => VersionHeader::encrypted(FORMAT_VERSION, key),
+                None => VersionHeader::new(FORMAT_VERSION),
+            };
+            #[cfg(not(feature = "encrypt"))]
+            let mut header = VersionHeader::new(FORMAT_VERSION);
+            header.alignment = LeU32::new(alignment as u32);
+            if header_length(alignment) > max_size {
+                return Err(LoadError::PileTooLarge);
+            }
+            file.write_all(&padded_header(header.as_bytes(), alignment))?;
+            file_len = header_length(alignment);
+            version = FORMAT_VERSION;
+        }
+
+        if let Storage::Mapped(mmap) = &storage {
+            let _ = advise_mapping(&mmap.read().unwrap(), AccessPattern::Normal);
+        }
+        let branches = RwLock::new(branches);
+        let heads = RwLock::new(heads);
+
+        let sync_file = Mutex::new(file.try_clone()?);
+        let file = Mutex::new(AppendFile {
+            file,
+            length: file_len,
+            unsynced_records: 0,
+            last_synced_length: file_len,
+            #[cfg(all(feature = "io_uring", target_os = "linux"))]
+            ring: if options.io_uring && !read_only {
+                io_uring::IoUring::new(RING_ENTRIES)
+                    .inspect_err(|err| log::warn!("falling back to regular writes: {err}"))
+                    .ok()
+            } else {
+                None
+            },
+        });
+
+        let pile = Self {
+            file,
+            sync_file,
+            path: path.map(Path::to_path_buf),
+            storage,
+            max_size,
+            read_only,
+            version,
+            alignment,
+            durability: options.durability,
+            options,
+            access_pattern: Mutex::new(AccessPattern::Normal),
+            bloom,
+            validation_hook: None,
+            validation_cache: options.validation_cache.map(ValidationCache::new),
+            subscribers: Mutex::new(Vec::new()),
+            insert_counters: InsertCounters::default(),
+            footer: Mutex::new(footer),
+            footer_hasher: Mutex::new(None),
+            index,
+            branches,
+            heads,
+            #[cfg(feature = "encrypt")]
+            encryption_key: options.encryption_key,
+            #[cfg(feature = "encrypt")]
+            plaintext_cache: Mutex::new(PlaintextCache::new(PLAINTEXT_CACHE_SIZE)),
+            _hasher: PhantomData,
+        };
+
+        Ok((pile, report))
+    }
+
+    /// Truncates the file at `path` to the records before the first corrupted
+    /// or incomplete record, so that it can be loaded again.
+    ///
+    /// Unlike `load` and `load_lenient`, which never cut off records by themselves,
+    /// **this discards everything after the first invalid record**, including intact
+    /// records behind it. Run it on a copy of the file to keep the discarded bytes.
+    pub fn repair(path: &Path) -> Result<RepairReport, LoadError> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+        let mut report = RepairReport {
+            records_kept: 0,
+            discarded_bytes: 0,
+        };
+        if file_len == 0 {
+            return Ok(report);
+        }
+
+        let valid_length = {
+            let storage = Storage::Read(file.try_clone()?);
+            let (version, alignment, start) = match read_version(&storage, file_len)? {
+                Some(header) => {
+                    let alignment = header.alignment();
+                    (header.version, alignment, header_length(alignment))
+                }
+                None => (0, DEFAULT_ALIGNMENT, 0),
+            };
+
+            let (records, valid_length) =
+                intact_records(&storage, start, file_len, version, alignment)?;
+            report.records_kept = records;
+            valid_length
+        };
+
+        report.discarded_bytes = file_len - valid_length;
+        if report.discarded_bytes > 0 {
+            file.set_len(valid_length as u64)?;
+        }
+        Ok(report)
+    }
+
+    /// Sets a function that is called whenever an unvalidated blob is hashed,
+    /// with the blob's hash and whether the blob matched it.
+    ///
+    /// This happens on the first read of a blob that was loaded from the file,
+    /// or during `validate_all` and `compact`.
+    ///
+    /// The hook runs while the pile holds the locks of the blob and its index shard,
+    /// so it must not call back into the pile: reading or inserting a blob from
+    /// the hook can deadlock. Send the results elsewhere, e.g. over a channel, instead.
+    pub fn with_validation_hook(
+        mut self,
+        hook: impl Fn(Hash, bool) + Send + Sync + 'static,
+    ) -> Self {
+        self.validation_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Returns the file format version of the pile.
+    pub fn format_version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns the alignment of the records and blobs in the pile.
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    /// Returns whether blobs are encrypted before they are written.
+    pub fn is_encrypted(&self) -> bool {
+        #[cfg(feature = "encrypt")]
+        {
+            self.encryption_key.is_some()
+        }
+        #[cfg(not(feature = "encrypt"))]
+        {
+            false
+        }
+    }
+
+    // Returns the bytes to store for a blob, which are encrypted if the pile is.
+    #[cfg_attr(not(feature = "encrypt"), allow(unused_variables))]
+    fn encrypt(&self, hash: &Hash, value: &Bytes) -> Bytes {
+        #[cfg(feature = "encrypt")]
+        if let Some(key) = &self.encryption_key {
+            return encrypt_blob(key, hash, value);
+        }
+        value.clone()
+    }
+
+    // Returns the bytes of a blob. Compressed blobs are decompressed into memory on first
+    // access, encrypted ones stay encrypted in the index and are decrypted on every access
+    // that misses the plaintext cache. Blobs that fail to decrypt or decompress are invalid.
+    fn decode(&self, hash: &Hash, entry: &mut IndexEntry) -> Bytes {
+        if !self.read_in(hash, entry) {
+            return Bytes::empty();
+        }
+        #[cfg(feature = "encrypt")]
+        if entry.encrypted {
+            return self.decrypt(hash, entry).unwrap_or_else(|| {
+                entry.state = ValidationState::Invalid;
+                Bytes::empty()
+            });
+        }
+        entry.decompress();
+        entry.bytes.clone()
+    }
+
+    #[cfg(feature = "encrypt")]
+    fn decrypt(&self, hash: &Hash, entry: &IndexEntry) -> Option<Bytes> {
+        if let Some(plaintext) = self.plaintext_cache.lock().unwrap().get(entry.offset) {
+            return Some(plaintext);
+        }
+        let key = self.encryption_key.as_ref()?;
+        let nonce = self.read_nonce(entry.offset).ok()?;
+        let plaintext = decrypt_blob(key, hash, &nonce, &entry.bytes)?;
+        #[cfg(feature = "zstd")]
+        let plaintext = if entry.compressed {
+            Bytes::from_source(zstd::decode_all(&plaintext[..]).ok()?)
+        } else {
+            plaintext
+        };
+        let mut cache = self.plaintext_cache.lock().unwrap();
+        cache.insert(entry.offset, plaintext.clone());
+        Some(plaintext)
+    }
+
+    // Reads the nonce of an encrypted blob from behind the `BlobHeader` of its record.
+    #[cfg(feature = "encrypt")]
+    fn read_nonce(&self, offset: usize) -> Result<Bytes, std::io::Error> {
+        let header_offset = offset - header_length(self.alignment);
+        self.storage.read(header_offset + size_of::<BlobHeader>(), NONCE_LEN)
+    }
+
+    // Splits the stored form of a blob into the bytes that follow the `BlobHeader`
+    // in the record header, and the bytes of the blob in the record.
+    // Encrypted piles keep the nonce in the header.
+    fn split_stored(&self, stored: &Bytes) -> (Bytes, Bytes) {
+        #[cfg(feature = "encrypt")]
+        if self.is_encrypted() {
+            let mut bytes = stored.clone();
+            let nonce = bytes.take_prefix(NONCE_LEN).expect("sealed blobs start with a nonce");
+            return (nonce, bytes);
+        }
+        (Bytes::empty(), stored.clone())
+    }
+
+    // Reads the bytes of entries of piles without a mapping, returns whether
+    // the entry has its bytes. Blobs that can't be read are treated as invalid,
+    // until the pile is loaded again.
+    fn read_in(&self, hash: &Hash, entry: &mut IndexEntry) -> bool {
+        if !entry.unread {
+            return true;
+        }
+        match self.storage.read(entry.offset, entry.stored_length) {
+            Ok(bytes) => {
+                entry.bytes = bytes;
+                entry.unread = false;
+                true
+            }
+            Err(err) => {
+                log::error!("failed to read blob {}: {err}", HexHash(hash));
+                entry.state = ValidationState::Invalid;
+                false
+            }
+        }
+    }
+
+    /// Hints the operating system how the blobs are going to be read,
+    /// e.g. `Random` for point lookups in a pile much larger than memory.
+    ///
+    /// The hint also applies to mappings created when the pile grows.
+    /// Loading always reads the file sequentially and hints that on its own.
+    pub fn advise(&self, pattern: AccessPattern) -> Result<(), std::io::Error> {
+        let mut access_pattern = self.access_pattern.lock().unwrap();
+        if let Storage::Mapped(mmap) = &self.storage {
+            advise_mapping(&mmap.read().unwrap(), pattern)?;
+        }
+        *access_pattern = pattern;
+        Ok(())
+    }
+
+    fn ensure_mapped(&self, file: &File, length: usize) -> Result<(), std::io::Error> {
+        let Storage::Mapped(mmap) = &self.storage else {
+            return Ok(());
+        };
+        let mut mmap = mmap.write().unwrap();
+        if length <= mmap.len() {
+            return Ok(());
+        }
+        let mapping_len = (mmap.len() * 2)
+            .max(length.next_power_of_two())
+            .min(self.max_size);
+        let grown = MmapOptions::new()
+            .len(mapping_len)
+            .map_raw_read_only(file)?;
+        let _ = advise_mapping(&grown, *self.access_pattern.lock().unwrap());
+        *mmap = Arc::new(grown);
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip_all,
+            fields(
+                hash = %HexHash(&hash[0..8]),
+                size = value.len(),
+                duplicate = tracing::field::Empty
+            )
+        )
+    )]
+    fn insert_blob_raw(
+        &self,
+        hash: Hash,
+        timestamp: u64,
+        validation: ValidationState,
+        value: &Bytes,
+        compressed: bool,
+    ) -> Result<InsertedBlob, InsertError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly);
+        }
+        self.check_blob_size(value.len())?;
+
+        let mut append = self.file.lock()?;
+
+        let existing = self.existing_bytes(&hash);
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("duplicate", existing.is_some());
+        if let Some((existing, record_offset)) = existing {
+            self.insert_counters.deduplicated(value.len());
+            return Ok(InsertedBlob {
+                bytes: existing,
+                added: false,
+                record_offset,
+            });
+        }
+
+        let stored = self.encrypt(&hash, value);
+        let (written_bytes, record_offset) =
+            self.append_blob(&mut append, hash, timestamp, validation, &stored, compressed)?;
+
+        // Callers get the bytes they passed in, not the ciphertext.
+        let bytes = if self.is_encrypted() {
+            value.clone()
+        } else {
+            written_bytes
+        };
+        Ok(InsertedBlob {
+            bytes,
+            added: true,
+            record_offset,
+        })
+    }
+
+    // Like `insert_blob_raw`, but for bytes that are already in their stored form,
+    // compressed or encrypted with this pile's key, which are written as they are.
+    fn insert_stored(
+        &self,
+        hash: Hash,
+        timestamp: u64,
+        stored: &Bytes,
+        compressed: bool,
+    ) -> Result<(), InsertError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly);
+        }
+        let mut append = self.file.lock()?;
+        if self.existing_bytes(&hash).is_none() {
+            let validation = ValidationState::Validated;
+            self.append_blob(&mut append, hash, timestamp, validation, stored, compressed)?;
+        }
+        Ok(())
+    }
+
+    // Appends the record of a blob's stored bytes and indexes it,
+    // returns the bytes as written and the offset of the record.
+    fn append_blob(
+        &self,
+        append: &mut AppendFile,
+        hash: Hash,
+        timestamp: u64,
+        validation: ValidationState,
+        stored: &Bytes,
+        compressed: bool,
+    ) -> Result<(Bytes, usize), InsertError> {
+        let (header_tail, stored) = self.split_stored(stored);
+        let old_length = append.length;
+        let padding = padding_for_blob(stored.len(), self.alignment);
+
+        let new_length =
+            self.appended_length(old_length, blob_record_length(stored.len(), self.alignment))?;
+
+        self.ensure_mapped(&append.file, new_length)?;
+
+        append.length = new_length;
+
+        #[cfg(feature = "zstd")]
+        let header = if compressed {
+            BlobHeader::compressed(timestamp, stored.len() as u64, hash)
+        } else {
+            BlobHeader::new(timestamp, stored.len() as u64, hash)
+        };
+        #[cfg(not(feature = "zstd"))]
+        let header = BlobHeader::new(timestamp, stored.len() as u64, hash);
+
+        let checksum = checksum_for_blob(&stored, self.alignment);
+        let offset = old_length + header_length(self.alignment);
+
+        append.write_record(&[
+            &padded_header(&[header.as_bytes(), &header_tail].concat(), self.alignment),
+            &stored,
+            &padding_with_checksum(checksum, padding),
+        ])?;
+        self.insert_counters.written(stored.len());
+
+        let written_bytes = self.storage.written(offset, &stored);
+
+        self.remember_hash(&hash);
+        let mut index = self.index.shard(&hash).write()?;
+        index.insert(
+            hash,
+            Mutex::new(IndexEntry {
+                state: validation,
+                validated_at: self.validation_tick(),
+                timestamp,
+                offset,
+                stored_length: stored.len(),
+                bytes: written_bytes.clone(),
+                compressed,
+                encrypted: self.is_encrypted(),
+                checksum,
+                unread: false,
+            }),
+        );
+        drop(index);
+        self.notify(&hash);
+        append.appended(1, self.durability)?;
+
+        Ok((written_bytes, old_length))
+    }
+
+    // The size of the largest blob that fits into this pile when it is empty.
+    fn max_blob_size(&self) -> usize {
+        let header_length = header_length(self.alignment);
+        let max = self.max_size.saturating_sub(2 * header_length) / self.alignment * self.alignment;
+        #[cfg(feature = "encrypt")]
+        if self.is_encrypted() {
+            return max.saturating_sub(ENCRYPTION_OVERHEAD);
+        }
+        max
+    }
+
+    // Rejects blobs that could never be stored before spending time on hashing them.
+    fn check_blob_size(&self, size: usize) -> Result<(), InsertError> {
+        let max = self.max_blob_size();
+        if size > max {
+            return Err(InsertError::BlobTooLarge { size, max });
+        }
+        Ok(())
+    }
+
+    // The file length after appending a record, if it stays within the size limit.
+    // With a limit close to `usize::MAX` the sum could wrap around, so it is checked.
+    fn appended_length(&self, length: usize, record_length: usize) -> Result<usize, InsertError> {
+        length
+            .checked_add(record_length)
+            .filter(|&new_length| new_length <= self.max_size)
+            .ok_or(InsertError::PileTooLarge)
+    }
+
+    /// Returns a channel that receives the hash of every blob inserted into
+    /// this pile from now on, in the order they were appended.
+    ///
+    /// Only inserts through this `Pile` are reported, not those of other
+    /// processes appending to the same file. Blobs that were already stored
+    /// are not reported again. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Hash> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    // Called with the file lock held, so subscribers see hashes in file order.
+    fn notify(&self, hash: &Hash) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.send(*hash).is_ok());
+    }
+
+    // Blobs that are known to be invalid are treated as absent,
+    // so that inserting them again replaces the corrupted copy.
+    // Also returns the offset of the blob's record.
+    fn existing_bytes(&self, hash: &Hash) -> Option<(Bytes, usize)> {
+        let index = self.index.shard(hash).read().unwrap();
+        let mut entry = index.get(hash)?.lock().unwrap();
+        let bytes = self.decode(hash, &mut entry);
+        match entry.state {
+            ValidationState::Invalid => None,
+            _ => Some((bytes, entry.offset - header_length(self.alignment))),
+        }
+    }
+
+    /// Stores a blob and returns its hash.
+    ///
+    /// Empty blobs are allowed, they are stored as a header without any padding.
+    pub fn insert_blob(&self, value: &Bytes) -> Result<Hash, InsertError> {
+        self.insert_if_absent(value).map(|(hash, _)| hash)
+    }
+
+    /// Like `insert_blob`, but also returns whether the blob was newly stored,
+    /// `false` means that it was already present.
+    pub fn insert_if_absent(&self, value: &Bytes) -> Result<(Hash, bool), InsertError> {
+        self.check_blob_size(value.len())?;
+        let hash: Hash = H::digest(value).into();
+
+        let inserted =
+            self.insert_blob_raw(hash, now_in_ms()?, ValidationState::Validated, value, false)?;
+
+        Ok((hash, inserted.added))
+    }
+
+    /// Like `insert_blob`, but also returns the offset in the file at which the
+    /// blob's record starts.
+    ///
+    /// Records are only appended, so every newly stored blob gets a larger offset
+    /// than all records before it, which makes offsets usable as a cursor, e.g. to
+    /// remember up to where a log of inserts has been processed. For a blob that
+    /// was already stored nothing is written and the offset of the existing record
+    /// is returned, which is smaller than that of the records appended since.
+    ///
+    /// Offsets stay valid as long as the file is only appended to. `compact_in_place`,
+    /// `gc` and `truncate_to` rewrite the file, which moves records to new offsets.
+    pub fn insert_at(&self, value: &Bytes) -> Result<(Hash, u64), InsertError> {
+        self.check_blob_size(value.len())?;
+        let hash: Hash = H::digest(value).into();
+
+        let inserted =
+            self.insert_blob_raw(hash, now_in_ms()?, ValidationState::Validated, value, false)?;
+
+        Ok((hash, inserted.record_offset as u64))
+    }
+
+    /// Like `insert_blob`, but stores `timestamp_ms` as the insertion time instead
+    /// of the current time, e.g. to keep the original times when importing data.
+    ///
+    /// Blobs that are already stored keep their timestamp.
+    /// Inserting the same blobs with the same timestamps into new piles
+    /// produces identical files.
+    pub fn insert_with_timestamp(
+        &self,
+        value: &Bytes,
+        timestamp_ms: u64,
+    ) -> Result<Hash, InsertError> {
+        self.check_blob_size(value.len())?;
+        let hash: Hash = H::digest(value).into();
+
+        self.insert_blob_raw(hash, timestamp_ms, ValidationState::Validated, value, false)?;
+
+        Ok(hash)
+    }
+
+    /// Like `insert_blob_validated`, but with a given timestamp, see `insert_with_timestamp`.
+    pub fn insert_validated_with_timestamp(
+        &self,
+        hash: Hash,
+        value: &Bytes,
+        timestamp_ms: u64,
+    ) -> Result<Bytes, InsertError> {
+        self.insert_blob_raw(
+            hash,
+            timestamp_ms,
+            ValidationState::Validated,
+            value,
+            false,
+        )
+        .map(|inserted| inserted.bytes)
+    }
+
+    /// Like `insert_blob_unvalidated`, but with a given timestamp, see `insert_with_timestamp`.
+    pub fn insert_unvalidated_with_timestamp(
+        &self,
+        hash: Hash,
+        value: &Bytes,
+        timestamp_ms: u64,
+    ) -> Result<Bytes, InsertError> {
+        self.insert_blob_raw(
+            hash,
+            timestamp_ms,
+            ValidationState::Unvalidated,
+            value,
+            false,
+        )
+        .map(|inserted| inserted.bytes)
+    }
+
+    /// Like `insert_blob`, but copies the blob from a slice.
+    pub fn insert_slice(&self, data: &[u8]) -> Result<Hash, InsertError> {
+        self.insert_blob(&Bytes::from_source(data.to_vec()))
+    }
+
+    /// Like `insert_blob`, but takes ownership of the blob without copying it.
+    pub fn insert_vec(&self, data: Vec<u8>) -> Result<Hash, InsertError> {
+        self.insert_blob(&Bytes::from_source(data))
+    }
+
+    pub fn insert_blob_validated(&self, hash: Hash, value: &Bytes) -> Result<Bytes, InsertError> {
+        self.insert_blob_raw(hash, now_in_ms()?, ValidationState::Validated, value, false)
+            .map(|inserted| inserted.bytes)
+    }
+
+    pub fn insert_blob_unvalidated(&self, hash: Hash, value: &Bytes) -> Result<Bytes, InsertError> {
+        self.insert_blob_raw(hash, now_in_ms()?, ValidationState::Unvalidated, value, false)
+            .map(|inserted| inserted.bytes)
+    }
+
+    /// Like `insert_blob`, but streams the blob from `reader` instead of
+    /// holding it in memory, for blobs that are too large for that.
+    ///
+    /// The blob is hashed while it is copied to a temporary file next to the pile,
+    /// and appended from there once the hash is known, header first. Like any other
+    /// insert, an interrupted one leaves at most a partial record at the end of the
+    /// file, which `load_lenient` and `repair` cut off. On errors the file is
+    /// truncated to its previous length.
+    ///
+    /// Encrypted piles can only encrypt whole blobs, so there the blob is read
+    /// into memory first.
+    pub fn insert_reader<R: Read>(&self, mut reader: R) -> Result<Hash, InsertError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly);
+        }
+
+        if self.is_encrypted() {
+            let mut value = Vec::new();
+            reader.read_to_end(&mut value)?;
+            return self.insert_blob(&Bytes::from_source(value));
+        }
+
+        let timestamp = now_in_ms()?;
+        let mut spooled = self.spool_blob(reader)?;
+        let (hash, length) = (spooled.hash, spooled.length);
+
+        let mut append = self.file.lock()?;
+        if self.existing_bytes(&hash).is_some() {
+            self.insert_counters.deduplicated(length);
+            return Ok(hash);
+        }
+
+        let old_length = append.length;
+        let new_length =
+            self.appended_length(old_length, blob_record_length(length, self.alignment))?;
+        self.ensure_mapped(&append.file, new_length)?;
+        if let Err(err) = self.append_spooled(&mut append.file, &mut spooled, timestamp) {
+            append.file.set_len(old_length as u64)?;
+            return Err(err);
+        }
+        append.length = new_length;
+        self.insert_counters.written(length);
+
+        // Piles without a mapping read the blob on first access, like loaded ones.
+        let unread = matches!(self.storage, Storage::Read(_));
+        let offset = old_length + header_length(self.alignment);
+        self.remember_hash(&hash);
+        let mut index = self.index.shard(&hash).write()?;
+        index.insert(
+            hash,
+            Mutex::new(IndexEntry {
+                state: ValidationState::Validated,
+                validated_at: self.validation_tick(),
+                timestamp,
+                offset,
+                stored_length: length,
+                bytes: if unread {
+                    Bytes::empty()
+                } else {
+                    self.storage.read(offset, length)?
+                },
+                compressed: false,
+                encrypted: false,
+                checksum: spooled.checksum,
+                unread,
+            }),
+        );
+        drop(index);
+        self.notify(&hash);
+        append.appended(1, self.durability)?;
+
+        Ok(hash)
+    }
+
+    // Copies the contents of `reader` to a temporary file and hashes them on the way.
+    // Blobs that can't fit into the rest of the pile are rejected early.
+    fn spool_blob<R: Read>(&self, mut reader: R) -> Result<SpooledBlob, InsertError> {
+        static SPOOLED: AtomicU64 = AtomicU64::new(0);
+
+        let name = format!(
+            "trible-pile-{}-{}.spool",
+            std::process::id(),
+            SPOOLED.fetch_add(1, Ordering::Relaxed)
+        );
+        // Next to the pile, so the blob doesn't end up in a memory backed temp dir.
+        let path = match &self.path {
+            Some(path) => path.with_file_name(name),
+            None => std::env::temp_dir().join(name),
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut spooled = SpooledBlob {
+            file,
+            path,
+            hash: [0; 32],
+            length: 0,
+            checksum: None,
+        };
+
+        let remaining = self.remaining_capacity();
+        let mut hasher = H::new();
+        let mut crc = 0;
+        let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+        loop {
+            let read = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            spooled.length += read;
+            if blob_record_length(spooled.length, self.alignment) > remaining {
+                return Err(InsertError::PileTooLarge);
+            }
+            Digest::update(&mut hasher, &chunk[..read]);
+            crc = crc32c::crc32c_append(crc, &chunk[..read]);
+            spooled.file.write_all(&chunk[..read])?;
+        }
+
+        spooled.hash = hasher.finalize().into();
+        spooled.checksum = (padding_for_blob(spooled.length, self.alignment) >= 4).then_some(crc);
+        Ok(spooled)
+    }
+
+    // Appends the record of a spooled blob to `file`, the end of the pile.
+    fn append_spooled(
+        &self,
+        file: &mut File,
+        spooled: &mut SpooledBlob,
+        timestamp: u64,
+    ) -> Result<(), InsertError> {
+        let header = BlobHeader::new(timestamp, spooled.length as u64, spooled.hash);
+        file.write_all(&padded_header(header.as_bytes(), self.alignment))?;
+
+        spooled.file.seek(SeekFrom::Start(0))?;
+        let copied = std::io::copy(&mut spooled.file, file)?;
+        if copied != spooled.length as u64 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let padding = padding_for_blob(spooled.length, self.alignment);
+        file.write_all(&padding_with_checksum(spooled.checksum, padding))?;
+        Ok(())
+    }
+
+    /// Like `insert_blob`, but stores the blob zstd compressed.
+    ///
+    /// The blob is still addressed by the hash of its uncompressed bytes
+    /// and is transparently decompressed when it is read.
+    #[cfg(feature = "zstd")]
+    pub fn insert_blob_compressed(&self, value: &Bytes) -> Result<Hash, InsertError> {
+        let hash: Hash = H::digest(value).into();
+
+        if self.existing_bytes(&hash).is_some() {
+            self.insert_counters.deduplicated(value.len());
+            return Ok(hash);
+        }
+
+        let compressed = Bytes::from_source(zstd::encode_all(&value[..], 0)?);
+        self.insert_blob_raw(
+            hash,
+            now_in_ms()?,
+            ValidationState::Validated,
+            &compressed,
+            true,
+        )?;
+
+        Ok(hash)
+    }
+
+    /// Imports a stream of blobs that are each preceded by their length
+    /// as a little-endian `u64`, e.g. a dump of another store, and returns
+    /// their hashes in stream order.
+    ///
+    /// The stream is read one blob at a time. Blobs of up to 64 KiB are read into memory,
+    /// larger ones are streamed like by `insert_reader`, so they are never held in memory
+    /// as a whole. If reading or storing a blob fails, the blobs before it stay in the
+    /// pile and their hashes are returned with the error in `ImportInterrupted`.
+    pub fn import_lengthprefixed<R: Read>(&self, mut reader: R) -> Result<Vec<Hash>, InsertError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly);
+        }
+
+        let mut imported = Vec::new();
+        loop {
+            match self.import_next(&mut reader) {
+                Ok(Some(hash)) => imported.push(hash),
+                Ok(None) => return Ok(imported),
+                Err(error) => {
+                    return Err(InsertError::ImportInterrupted {
+                        imported,
+                        error: Box::new(error),
+                    })
+                }
+            }
+        }
+    }
+
+    // Reads and stores the next blob of `import_lengthprefixed`,
+    // returns `None` at the end of the stream.
+    fn import_next<R: Read>(&self, reader: &mut R) -> Result<Option<Hash>, InsertError> {
+        let Some(length) = read_length_prefix(reader)? else {
+            return Ok(None);
+        };
+        // Checked before reading, so a corrupted length fails before any bytes are read.
+        let size = usize::try_from(length).unwrap_or(usize::MAX);
+        self.check_blob_size(size)?;
+
+        let mut reader = ExactReader {
+            inner: reader,
+            remaining: length,
+        };
+        if size > STREAM_CHUNK_SIZE {
+            return self.insert_reader(reader).map(Some);
+        }
+        let mut value = Vec::with_capacity(size);
+        reader.read_to_end(&mut value)?;
+        self.insert_vec(value).map(Some)
+    }
+
+    /// Inserts a batch of blobs with a single append to the file.
+    pub fn insert_many(&self, values: &[Bytes]) -> Result<Vec<Hash>, InsertError> {
+        let timestamp = now_in_ms()?;
+
+        for value in values {
+            self.check_blob_size(value.len())?;
+        }
+
+        let mut hashes = Vec::with_capacity(values.len());
+        let mut blobs = Vec::with_capacity(values.len());
+        for value in values {
+            let hash: Hash = H::digest(value).into();
+            hashes.push(hash);
+            blobs.push(PendingBlob {
+                hash,
+                timestamp,
+                state: ValidationState::Validated,
+                bytes: value.clone(),
+            });
+        }
+
+        self.insert_batch_raw(&blobs)?;
+
+        Ok(hashes)
+    }
+
+    // Appends all blobs that aren't stored yet with a single write
+    // and returns how many were written.
+    fn insert_batch_raw(&self, blobs: &[PendingBlob]) -> Result<usize, InsertError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly);
+        }
+
+        let mut append = self.file.lock()?;
+
+        let mut seen = HashSet::new();
+        let (pending, duplicates): (Vec<&PendingBlob>, Vec<&PendingBlob>) = blobs
+            .iter()
+            .partition(|blob| seen.insert(blob.hash) && self.existing_bytes(&blob.hash).is_none());
+
+        let (header_tails, stored): (Vec<Bytes>, Vec<Bytes>) = pending
+            .iter()
+            .map(|blob| self.split_stored(&self.encrypt(&blob.hash, &blob.bytes)))
+            .unzip();
+
+        let old_length = append.length;
+        let batch_length = stored
+            .iter()
+            .map(|bytes| blob_record_length(bytes.len(), self.alignment))
+            .try_fold(0usize, usize::checked_add)
+            .ok_or(InsertError::PileTooLarge)?;
+
+        let new_length = self.appended_length(old_length, batch_length)?;
+
+        self.ensure_mapped(&append.file, new_length)?;
+
+        append.length = new_length;
+
+        let checksums: Vec<Option<u32>> = stored
+            .iter()
+            .map(|bytes| checksum_for_blob(bytes, self.alignment))
+            .collect();
+
+        let mut buffer = Vec::with_capacity(batch_length);
+        for (((blob, bytes), checksum), header_tail) in
+            pending.iter().zip(&stored).zip(&checksums).zip(&header_tails)
+        {
+            let header = BlobHeader::new(blob.timestamp, bytes.len() as u64, blob.hash);
+            let padding = padding_for_blob(bytes.len(), self.alignment);
+            let header = [header.as_bytes(), header_tail].concat();
+            buffer.extend_from_slice(&padded_header(&header, self.alignment));
+            buffer.extend_from_slice(bytes);
+            buffer.extend_from_slice(&padding_with_checksum(*checksum, padding));
+        }
+
+        append.file.write_all(&buffer)?;
+        for bytes in &stored {
+            self.insert_counters.written(bytes.len());
+        }
+        for blob in duplicates {
+            self.insert_counters.deduplicated(blob.bytes.len());
+        }
+
+        let mut offset = old_length;
+        for ((blob, bytes), checksum) in pending.iter().zip(&stored).zip(checksums) {
+            self.remember_hash(&blob.hash);
+            let mut index = self.index.shard(&blob.hash).write()?;
+            index.insert(
+                blob.hash,
+                Mutex::new(IndexEntry {
+                    state: blob.state,
+                    validated_at: self.validation_tick(),
+                    timestamp: blob.timestamp,
+                    offset: offset + header_length(self.alignment),
+                    stored_length: bytes.len(),
+                    bytes: self.storage.written(offset + header_length(self.alignment), bytes),
+                    compressed: false,
+                    encrypted: self.is_encrypted(),
+                    checksum,
+                    unread: false,
+                }),
+            );
+            drop(index);
+            self.notify(&blob.hash);
+            offset += blob_record_length(bytes.len(), self.alignment);
+        }
+        append.appended(pending.len(), self.durability)?;
+
+        Ok(pending.len())
+    }
+
+    /// Copies all blobs that `dest` doesn't contain yet with a single append,
+    /// and returns how many were copied.
+    ///
+    /// Blobs keep their timestamps and validation state,
+    /// blobs that are known to be invalid aren't copied.
+    pub fn copy_to<const N: usize>(&self, dest: &Pile<N, H>) -> Result<usize, InsertError> {
+        Ok(dest.merge_from(self)?.added)
+    }
+
+    /// Returns the hashes of all blobs in this pile that `other` doesn't contain,
+    /// e.g. to find out what to send to a peer before `copy_to`.
+    ///
+    /// The result takes 32 bytes per missing blob. For huge differences, going
+    /// through `hashes` and checking `other.contains` avoids holding them all.
+    pub fn diff<const N: usize>(&self, other: &Pile<N, H>) -> Vec<Hash> {
+        let mut missing = Vec::new();
+        for shard in self.index.shards() {
+            let index = shard.read().unwrap();
+            missing.extend(index.keys().filter(|hash| !other.contains(hash)));
+        }
+        missing
+    }
+
+    /// Adds all blobs of `other` that are missing from this pile with a single append,
+    /// the counterpart of `copy_to`.
+    ///
+    /// Blobs keep their timestamps and validation state.
+    /// Blobs that are known to be invalid in `other` are neither added nor counted.
+    pub fn merge_from<const N: usize>(
+        &self,
+        other: &Pile<N, H>,
+    ) -> Result<MergeReport, InsertError> {
+        let mut blobs = Vec::new();
+        let mut present = 0;
+        for shard in other.index.shards() {
+            let index = shard.read()?;
+            for (hash, blob) in index.iter() {
+                if self.contains(hash) {
+                    present += 1;
+                    continue;
+                }
+                let mut entry = blob.lock()?;
+                let bytes = other.decode(hash, &mut entry);
+                if entry.state != ValidationState::Invalid {
+                    blobs.push(PendingBlob {
+                        hash: *hash,
+                        timestamp: entry.timestamp,
+                        state: entry.state,
+                        bytes,
+                    });
+                }
+            }
+        }
+
+        let added = self.insert_batch_raw(&blobs)?;
+        Ok(MergeReport {
+            added,
+            skipped: present + blobs.len() - added,
+        })
+    }
+
+    /// Checks whether a blob is present without validating it,
+    /// so this also returns `true` for blobs that failed validation.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        if !self.probably_contains(hash) {
+            return false;
+        }
+        let index = self.index.shard(hash).read().unwrap();
+        index.contains_key(hash)
+    }
+
+    /// Returns a handle to a stored blob without reading it, see `BlobRef::fetch`.
+    pub fn get(&self, hash: &Hash) -> Option<BlobRef<'_, MAX_PILE_SIZE, H>> {
+        self.contains(hash).then_some(BlobRef {
+            pile: self,
+            hash: *hash,
+        })
+    }
+
+    /// Checks a Bloom filter of the stored hashes without looking at the index.
+    ///
+    /// `false` means the blob is definitely not present, `true` means it may be,
+    /// which `contains` confirms. `contains` already uses the filter for misses.
+    pub fn probably_contains(&self, hash: &Hash) -> bool {
+        self.bloom.read().unwrap_or_else(PoisonError::into_inner).contains(hash)
+    }
+
+    // Adds a hash to the Bloom filter before its blob is indexed. Once the filter holds
+    // more blobs than it was sized for, it is rebuilt from the index with room to grow.
+    // Callers hold the file lock, so no other hash can be missing from the index then.
+    fn remember_hash(&self, hash: &Hash) {
+        let bloom = self.bloom.read().unwrap_or_else(PoisonError::into_inner);
+        bloom.insert(hash);
+        if !bloom.is_full() {
+            return;
+        }
+        drop(bloom);
+        let mut bloom = self.bloom.write().unwrap_or_else(PoisonError::into_inner);
+        if bloom.is_full() {
+            *bloom = BloomFilter::from_index(&self.index);
+            bloom.insert(hash);
+        }
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip_all,
+            fields(
+                hash = %HexHash(&hash[0..8]),
+                cached = tracing::field::Empty,
+                valid = tracing::field::Empty
+            )
+        )
+    )]
+    pub fn get_blob(&self, hash: &Hash) -> Result<Option<Bytes>, GetError> {
+        let index = self.index.shard(hash).read().unwrap();
+        let Some(blob) = index.get(hash) else {
+            return Ok(None);
+        };
+        let mut entry = blob.lock().unwrap();
+        self.get_entry(hash, &mut entry).map(Some)
+    }
+
+    /// Like `get_blob`, but copies the blob to the heap instead of pointing into
+    /// the file's mapping.
+    ///
+    /// `Bytes` from `get_blob` keep the whole mapping alive, even after the pile
+    /// is dropped. Use this for blobs that are kept around long after reading
+    /// a few of them from a large pile, and `get_blob` otherwise, which doesn't copy.
+    pub fn get_owned(&self, hash: &Hash) -> Result<Option<Bytes>, GetError> {
+        self.with_bytes(hash, |bytes| Bytes::from_source(bytes.to_vec()))
+    }
+
+    /// Like `get_blob`, but also returns the blob's insertion time, see `timestamp`.
+    pub fn get_with_timestamp(&self, hash: &Hash) -> Result<Option<(Bytes, u64)>, GetError> {
+        let index = self.index.shard(hash).read()?;
+        let Some(blob) = index.get(hash) else {
+            return Ok(None);
+        };
+        let mut entry = blob.lock()?;
+        let bytes = self.get_entry(hash, &mut entry)?;
+        Ok(Some((bytes, entry.timestamp)))
+    }
+
+    /// Like `get_blob`, but calls `f` with the blob's bytes instead of returning them,
+    /// for callers that only parse or copy parts of a blob.
+    ///
+    /// The blob's lock is held while `f` runs, so `f` shouldn't access the same blob.
+    pub fn with_bytes<R>(
+        &self,
+        hash: &Hash,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Result<Option<R>, GetError> {
+        let index = self.index.shard(hash).read()?;
+        let Some(blob) = index.get(hash) else {
+            return Ok(None);
+        };
+        let mut entry = blob.lock()?;
+        let bytes = self.get_entry(hash, &mut entry)?;
+        Ok(Some(f(&bytes)))
+    }
+
+    /// Returns the blob with the given hash, or stores and returns the result of `f`.
+    ///
+    /// The output of `f` is checked against `hash` and isn't stored if it doesn't match.
+    /// A stored copy that fails validation is replaced like with `insert_blob`.
+    /// Concurrent calls for the same hash may each call `f`, but the blob is stored once.
+    pub fn get_or_insert_with<F: FnOnce() -> Bytes>(
+        &self,
+        hash: &Hash,
+        f: F,
+    ) -> Result<Bytes, GetError> {
+        match self.get_blob(hash) {
+            Ok(Some(bytes)) => return Ok(bytes),
+            Ok(None) | Err(GetError::ValidationError { .. }) => {}
+            Err(err) => return Err(err),
+        }
+        let bytes = f();
+        let actual: Hash = H::digest(&bytes).into();
+        if actual != *hash {
+            return Err(GetError::ValidationError {
+                expected: *hash,
+                actual,
+                bytes,
+            });
+        }
+        Ok(self.insert_blob_validated(*hash, &bytes)?)
+    }
+
+    /// Like `get_blob`, but only returns the bytes in `range`, without copying them.
+    ///
+    /// The whole blob is validated before any window of it is returned,
+    /// like by `get_verified_slice`.
+    pub fn get_slice(&self, hash: &Hash, range: Range<usize>) -> Result<Option<Bytes>, GetError> {
+        self.get_verified_slice(hash, range)
+    }
+
+    /// Returns the bytes in `range` of the blob, without copying them,
+    /// after checking the whole blob against its hash.
+    ///
+    /// Corruption outside of `range` is reported too. The blob's validation state
+    /// is the one `get_blob` uses, so this happens on the first access only,
+    /// reading successive windows doesn't validate the blob again.
+    pub fn get_verified_slice(
+        &self,
+        hash: &Hash,
+        range: Range<usize>,
+    ) -> Result<Option<Bytes>, GetError> {
+        let Some(mut bytes) = self.get_blob(hash)? else {
+            return Ok(None);
+        };
+        if range.start > range.end || range.end > bytes.len() {
+            return Err(GetError::OutOfRange {
+                range,
+                length: bytes.len(),
+            });
+        }
+        bytes.take_prefix(range.start);
+        Ok(bytes.take_prefix(range.len()))
+    }
+
+    /// Like `get_blob` for every hash, but only takes each index lock once.
+    ///
+    /// The results are in the same order as `hashes`.
+    pub fn get_many(&self, hashes: &[Hash]) -> Vec<Result<Option<Bytes>, GetError>> {
+        let shards: Vec<_> = self
+            .index
+            .shards()
+            .iter()
+            .map(|shard| shard.read().unwrap())
+            .collect();
+        hashes
+            .iter()
+            .map(|hash| {
+                let Some(blob) = shards[shard_of(hash)].get(hash) else {
+                    return Ok(None);
+                };
+                let mut entry = blob.lock()?;
+                self.get_entry(hash, &mut entry).map(Some)
+            })
+            .collect()
+    }
+
+    // Returns the bytes of an entry if they are valid, validating them if needed.
+    fn get_entry(&self, hash: &Hash, entry: &mut IndexEntry) -> Result<Bytes, GetError> {
+        #[cfg(feature = "tracing")]
+        let cached = !matches!(entry.state, ValidationState::Unvalidated);
+        let (state, bytes) = self.validated_bytes(hash, entry);
+        #[cfg(feature = "tracing")]
+        tracing::Span::current()
+            .record("cached", cached)
+            .record("valid", !matches!(state, ValidationState::Invalid));
+        match state {
+            ValidationState::Invalid => {
+                let computed_hash: Hash = H::digest(&bytes).into();
+                Err(GetError::ValidationError {
+                    expected: *hash,
+                    actual: computed_hash,
+                    bytes,
+                })
+            }
+            _ => Ok(bytes),
+        }
+    }
+
+    /// Like `get_blob`, but checks unvalidated blobs against their CRC32C checksum
+    /// instead of hashing them, which is much cheaper for large blobs.
+    ///
+    /// The checksum only catches accidental corruption like bit rot or torn writes,
+    /// it is not a security property: a blob can be modified to match it on purpose.
+    /// Blobs without a checksum, because they were written by an older version
+    /// or have less than 4 bytes of padding, are fully validated instead.
+    pub fn get_fast_checked(&self, hash: &Hash) -> Result<Option<Bytes>, GetError> {
+        {
+            let index = self.index.shard(hash).read()?;
+            let Some(blob) = index.get(hash) else {
+                return Ok(None);
+            };
+            let mut entry = blob.lock()?;
+            self.read_in(hash, &mut entry);
+            if let (ValidationState::Unvalidated, Some(checksum)) = (entry.state, entry.checksum) {
+                if crc32c::crc32c(&entry.bytes) == checksum {
+                    let bytes = self.decode(hash, &mut entry);
+                    if let ValidationState::Unvalidated = entry.state {
+                        return Ok(Some(bytes));
+                    }
+                } else {
+                    entry.state = ValidationState::Invalid;
+                }
+            }
+        }
+        self.get_blob(hash)
+    }
+
+    /// Returns all stored blobs with their hashes, without validating them.
+    ///
+    /// Blobs that are already known to be invalid are skipped.
+    /// Like `hashes`, the entries are snapshotted under the index read locks.
+    pub fn iter(&self) -> std::vec::IntoIter<(Hash, Bytes)> {
+        self.iter_timestamps(..)
+    }
+
+    /// Like `iter`, but only returns blobs inserted at or after `since_ms`,
+    /// in milliseconds since the unix epoch, e.g. for incremental exports.
+    ///
+    /// This checks the timestamp of every blob in the pile.
+    pub fn iter_since(&self, since_ms: u64) -> std::vec::IntoIter<(Hash, Bytes)> {
+        self.iter_timestamps(since_ms..)
+    }
+
+    /// Like `iter_since`, but only returns blobs inserted before `end_ms`.
+    pub fn iter_between(&self, start_ms: u64, end_ms: u64) -> std::vec::IntoIter<(Hash, Bytes)> {
+        self.iter_timestamps(start_ms..end_ms)
+    }
+
+    fn iter_timestamps(&self, range: impl RangeBounds<u64>) -> std::vec::IntoIter<(Hash, Bytes)> {
+        let mut entries: Vec<(Hash, Bytes)> = Vec::new();
+        for shard in self.index.shards() {
+            let index = shard.read().unwrap();
+            entries.extend(index.iter().filter_map(|(hash, blob)| {
+                let mut entry = blob.lock().unwrap();
+                if !range.contains(&entry.timestamp) {
+                    return None;
+                }
+                let bytes = self.decode(hash, &mut entry);
+                match entry.state {
+                    ValidationState::Invalid => None,
+                    _ => Some((*hash, bytes)),
+                }
+            }));
+        }
+        entries.into_iter()
+    }
+
+    /// Returns all blob records in the order they were written, without validating them.
+    ///
+    /// Unlike `iter` this walks the file instead of the index, so it also returns
+    /// blobs that were removed later and every copy of blobs inserted more than once.
+    /// A blob that fails to decompress or decrypt is returned as an `IoError`, and
+    /// the iteration continues behind it. A malformed record ends it with its error.
+    pub fn iter_in_order(&self) -> impl Iterator<Item = Result<(Hash, Bytes), LoadError>> + '_ {
+        let length = self.used();
+        let (mut offset, mut error) = match read_version(&self.storage, length) {
+            Ok(Some(_)) => (header_length(self.alignment), None),
+            Ok(None) => (0, None),
+            Err(err) => (length, Some(err)),
+        };
+        let mut malformed = false;
+        std::iter::from_fn(move || loop {
+            if malformed {
+                return None;
+            }
+            if let Some(err) = error.take() {
+                malformed = true;
+                return Some(Err(err));
+            }
+            match read_record(&self.storage, offset, length, self.version, self.alignment) {
+                Ok(Some((record, next))) => {
+                    let blob_offset = offset + header_length(self.alignment);
+                    offset = next;
+                    let Record::Blob {
+                        hash,
+                        length,
+                        compressed,
+                        ..
+                    } = record
+                    else {
+                        continue;
+                    };
+                    let bytes = match self.storage.read(blob_offset, length) {
+                        Ok(bytes) => bytes,
+                        Err(err) => return Some(Err(err.into())),
+                    };
+                    #[cfg(feature = "encrypt")]
+                    let bytes = match &self.encryption_key {
+                        Some(key) => match self
+                            .read_nonce(blob_offset)
+                            .ok()
+                            .and_then(|nonce| decrypt_blob(key, &hash, &nonce, &bytes))
+                        {
+                            Some(plaintext) => plaintext,
+                            None => {
+                                let err = std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    "failed to decrypt blob",
+                                );
+                                return Some(Err(err.into()));
+                            }
+                        },
+                        None => bytes,
+                    };
+                    #[cfg(feature = "zstd")]
+                    let bytes = if compressed {
+                        match zstd::decode_all(&bytes[..]) {
+                            Ok(decompressed) => Bytes::from_source(decompressed),
+                            Err(err) => return Some(Err(err.into())),
+                        }
+                    } else {
+                        bytes
+                    };
+                    #[cfg(not(feature = "zstd"))]
+                    debug_assert!(!compressed);
+                    return Some(Ok((hash, bytes)));
+                }
+                Ok(None) => return None,
+                Err(err) => {
+                    malformed = true;
+                    return Some(Err(err));
+                }
+            }
+        })
+    }
+
+    /// Asks the operating system to read the given blobs into the page cache
+    /// in the background, so that reading them later doesn't block on the disk.
+    ///
+    /// Unknown hashes and empty blobs are ignored, and so is everything on platforms
+    /// without `madvise`. Returns the number of blobs a hint was given for,
+    /// which is 0 for piles without a mapping.
+    pub fn prefetch(&self, hashes: &[Hash]) -> usize {
+        let ranges: Vec<(usize, usize)> = hashes
+            .iter()
+            .filter_map(|hash| {
+                let index = self.index.shard(hash).read().unwrap();
+                let entry = index.get(hash)?.lock().unwrap();
+                Some((entry.offset, entry.stored_length))
+            })
+            .filter(|&(_, length)| length > 0)
+            .collect();
+        let Storage::Mapped(mmap) = &self.storage else {
+            return 0;
+        };
+        let mmap = mmap.read().unwrap();
+        for &(offset, length) in &ranges {
+            // Hints are only an optimization, failing to apply them is harmless.
+            let _ = prefetch_range(&mmap, offset, length);
+        }
+        ranges.len()
+    }
+
+    /// Returns a blob without checking it against its hash.
+    ///
+    /// **This bypasses all integrity guarantees** and may return corrupted data,
+    /// even for blobs that are known to be invalid.
+    /// Only use it for blobs from a source you already trust.
+    pub fn get_unchecked(&self, hash: &Hash) -> Result<Option<Bytes>, GetError> {
+        let index = self.index.shard(hash).read()?;
+        let Some(blob) = index.get(hash) else {
+            return Ok(None);
+        };
+        let mut entry = blob.lock()?;
+        Ok(Some(self.decode(hash, &mut entry)))
+    }
+
+    /// Validates every unvalidated blob and returns the hashes of all invalid blobs.
+    ///
+    /// With the `rayon` feature, `validate_all_parallel` spreads the hashing over all cores.
+    pub fn validate_all(&self) -> Result<Vec<Hash>, GetError> {
+        let mut invalid = Vec::new();
+        for shard in self.index.shards() {
+            let index = shard.read()?;
+            for (hash, blob) in index.iter() {
+                let mut entry = blob.lock()?;
+                if let ValidationState::Invalid = self.validate_entry(hash, &mut entry) {
+                    invalid.push(*hash);
+                }
+            }
+        }
+        Ok(invalid)
+    }
+
+    /// Hashes the stored bytes of a blob again and returns whether they match,
+    /// without copying them out like `get_blob` does.
+    ///
+    /// Unlike reads this also checks blobs that were validated before,
+    /// and records the result like a read would. Fails with `NotFound` if the
+    /// blob isn't in the pile.
+    pub fn validate(&self, hash: &Hash) -> Result<bool, GetError> {
+        let index = self.index.shard(hash).read()?;
+        let mut entry = index.get(hash).ok_or(GetError::NotFound)?.lock()?;
+        if let ValidationState::Validated = entry.state {
+            entry.state = ValidationState::Unvalidated;
+        }
+        Ok(self.validate_entry(hash, &mut entry) == ValidationState::Validated)
+    }
+
+    // The tick to store in a newly validated entry, 0 without a validation cache.
+    fn validation_tick(&self) -> u64 {
+        self.validation_cache.as_ref().map_or(0, ValidationCache::next_tick)
+    }
+
+    fn validate_entry(&self, hash: &Hash, entry: &mut IndexEntry) -> ValidationState {
+        self.validated_bytes(hash, entry).0
+    }
+
+    // Validates the entry if needed, returns its state and its bytes.
+    fn validated_bytes(&self, hash: &Hash, entry: &mut IndexEntry) -> (ValidationState, Bytes) {
+        let bytes = self.decode(hash, entry);
+        if let (ValidationState::Validated, Some(cache)) = (entry.state, &self.validation_cache) {
+            // Blobs that dropped out of the cache are validated again.
+            if cache.is_fresh(entry.validated_at) {
+                entry.validated_at = cache.next_tick();
+            } else {
+                entry.state = ValidationState::Unvalidated;
+            }
+        }
+        if let ValidationState::Unvalidated = entry.state {
+            let computed_hash: Hash = H::digest(&bytes).into();
+            let valid = computed_hash == *hash;
+            if valid {
+                entry.state = ValidationState::Validated;
+                entry.validated_at = self.validation_tick();
+            } else {
+                entry.state = ValidationState::Invalid;
+            }
+            if let Some(hook) = &self.validation_hook {
+                hook(*hash, valid);
+            }
+        }
+        (entry.state, bytes)
+    }
+
+    /// Writes all valid blobs and the current branch heads into a fresh pile at `dest`.
+    ///
+    /// Removed blobs, superseded records and blobs that fail validation are dropped.
+    /// The compacted pile has the same alignment and is loaded like this one,
+    /// e.g. with the same encryption key, durability and mapping, but writable.
+    /// Blobs are copied as they are stored, so compressed blobs stay compressed.
+    /// Only blobs in the index are copied, blobs dropped by `retain` are lost.
+    pub fn compact(&self, dest: &Path) -> Result<Pile<MAX_PILE_SIZE, H>, LoadError> {
+        self.compact_retaining(dest, |_| true)
+    }
+
+    fn compact_retaining(
+        &self,
+        dest: &Path,
+        keep: impl Fn(&Hash) -> bool,
+    ) -> Result<Pile<MAX_PILE_SIZE, H>, LoadError> {
+        let (compacted, _) = Self::load_path(dest, self.copy_options())?;
+
+        for shard in self.index.shards() {
+            let index = shard.read().unwrap();
+            for (hash, blob) in index.iter().filter(|(hash, _)| keep(hash)) {
+                let mut entry = blob.lock().unwrap();
+                if let ValidationState::Validated = self.validate_entry(hash, &mut entry) {
+                    let (stored, compressed) = self.stored_bytes(&entry)?;
+                    compacted.insert_stored(*hash, entry.timestamp, &stored, compressed)?;
+                }
+            }
+        }
+
+        let branches = self.branches.read().unwrap();
+        for (branch_id, hash) in branches.iter() {
+            compacted.commit_branch(*branch_id, *hash)?;
+        }
+
+        let heads = self.heads.read().unwrap();
+        for (name, hash) in heads.iter() {
+            compacted.append_head(*name, *hash)?;
+        }
+
+        Ok(compacted)
+    }
+
+    /// Like `compact`, but replaces the pile's own file with the compacted one.
+    ///
+    /// The compacted pile is written to a sibling file with a `.compact` suffix,
+    /// synced, and then renamed over the original, which is atomic on the same
+    /// file system. A crash before the rename leaves the original untouched,
+    /// and the leftover temporary file is overwritten by the next attempt.
+    /// Since the temporary file is synced first, the original name never points
+    /// at a partially written pile, and the directory is synced after the rename
+    /// so that the rename itself survives a crash.
+    ///
+    /// `Bytes` returned before still point into the old mapping, which stays valid
+    /// until the last of them is dropped. Reads afterwards return bytes from the new file.
+    /// Other processes that have the old file open keep reading the old contents.
+    pub fn compact_in_place(&mut self) -> Result<(), LoadError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly.into());
+        }
+        let tmp_path = self.temporary_sibling(".compact")?;
+        let compacted = self.compact(&tmp_path)?;
+        self.replace_with(compacted, &tmp_path)
+    }
+
+    /// Drops every blob that isn't reachable from `roots`, by compacting the pile
+    /// in place like `compact_in_place`.
+    ///
+    /// Blobs reference each other by hash, `extract_refs` returns the hashes
+    /// referenced by the bytes of a blob, so the pile doesn't need to know their format.
+    /// The hashes of branches and heads count as roots too, so they stay valid.
+    /// Referenced hashes that aren't in the pile, and blobs that fail validation,
+    /// are skipped, the latter are dropped by the compaction anyway. Any other error
+    /// stops the collection before the file is touched.
+    pub fn gc(
+        &mut self,
+        roots: &[Hash],
+        extract_refs: impl Fn(&[u8]) -> Vec<Hash>,
+    ) -> Result<GcReport, LoadError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly.into());
+        }
+
+        let mut pending = roots.to_vec();
+        pending.extend(self.branches.read().map_err(InsertError::from)?.values());
+        pending.extend(self.heads.read().map_err(InsertError::from)?.values());
+        let mut reachable = HashSet::new();
+        while let Some(hash) = pending.pop() {
+            if !reachable.contains(&hash) && self.contains(&hash) {
+                reachable.insert(hash);
+                match self.with_bytes(&hash, &extract_refs) {
+                    Ok(refs) => pending.extend(refs.into_iter().flatten()),
+                    Err(GetError::ValidationError { .. }) => {}
+                    // Reads only fail otherwise on poisoned locks.
+                    Err(_) => return Err(InsertError::PoisonError.into()),
+                }
+            }
+        }
+
+        let blob_count = self.len();
+        let length = self.used();
+        let tmp_path = self.temporary_sibling(".gc")?;
+        let compacted = self.compact_retaining(&tmp_path, |hash| reachable.contains(hash))?;
+        self.replace_with(compacted, &tmp_path)?;
+        Ok(GcReport {
+            reachable: self.len(),
+            collected: blob_count - self.len(),
+            reclaimed_bytes: length.saturating_sub(self.used()),
+        })
+    }
+
+    /// Rolls the pile back to the state right after the first record of the blob
+    /// with the given hash was written, e.g. to undo a bad batch.
+    ///
+    /// Every record after it is dropped, including branch and head updates and
+    /// removals. This works like `compact_in_place`, the kept records are written
+    /// to a sibling file with a `.truncate` suffix that is renamed over the original,
+    /// so `Bytes` returned before stay valid. Fails with `NotFound` if the file
+    /// contains no record of the blob.
+    pub fn truncate_to(&mut self, hash: &Hash) -> Result<(), LoadError> {
+        self.truncate_after_record(hash, false)
+    }
+
+    /// Like `truncate_to`, but keeps everything up to the last record of the blob,
+    /// for blobs that were written more than once.
+    pub fn truncate_to_last(&mut self, hash: &Hash) -> Result<(), LoadError> {
+        self.truncate_after_record(hash, true)
+    }
+
+    fn truncate_after_record(&mut self, hash: &Hash, last: bool) -> Result<(), LoadError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly.into());
+        }
+        let tmp_path = self.temporary_sibling(".truncate")?;
+
+        let length = self.used();
+        let mut offset = match read_version(&self.storage, length)? {
+            Some(_) => header_length(self.alignment),
+            None => 0,
+        };
+        let mut end = None;
+        while let Some((record, next)) =
+            read_record(&self.storage, offset, length, self.version, self.alignment)?
+        {
+            if let Record::Blob {
+                hash: record_hash, ..
+            } = record
+            {
+                if record_hash == *hash {
+                    end = Some(next);
+                    if !last {
+                        break;
+                    }
+                }
+            }
+            offset = next;
+        }
+        let end = end.ok_or(LoadError::NotFound)?;
+
+        let mut tmp_file = File::create(&tmp_path)?;
+        self.storage.for_each_chunk(0..end, |chunk| tmp_file.write_all(chunk))?;
+        drop(tmp_file);
+        let (truncated, _) = Self::load_path(&tmp_path, self.copy_options())?;
+        self.replace_with(truncated, &tmp_path)
+    }
+
+    // Options for loading a copy of the pile, like its own, but writable and
+    // with the pile's alignment. The copy is complete, so it is loaded strictly.
+    fn copy_options(&self) -> LoadOptions {
+        LoadOptions {
+            alignment: self.alignment,
+            read_only: false,
+            lenient: false,
+            truncate_to_valid: false,
+            ..self.options
+        }
+    }
+
+    // Reads the stored form of a blob from the file, before decoding, and whether
+    // it is compressed. Reads decompress entries in place, so this takes both from
+    // the file instead of the entry. Encrypted blobs are preceded by their nonce.
+    fn stored_bytes(&self, entry: &IndexEntry) -> Result<(Bytes, bool), std::io::Error> {
+        let magic = self.storage.read(entry.offset - header_length(self.alignment), 16)?;
+        let compressed = magic[..] == MAGIC_MARKER_BLOB_ZSTD;
+        let stored = self.storage.read(entry.offset, entry.stored_length)?;
+        #[cfg(feature = "encrypt")]
+        if self.is_encrypted() {
+            let nonce = self.read_nonce(entry.offset)?;
+            return Ok((Bytes::from_source([&nonce[..], &stored[..]].concat()), compressed));
+        }
+        Ok((stored, compressed))
+    }
+
+    // Returns the sibling of the pile's file with the given suffix,
+    // after removing what an earlier, interrupted attempt left there.
+    fn temporary_sibling(&self, suffix: &str) -> Result<PathBuf, LoadError> {
+        let path = self.path.as_ref().ok_or(LoadError::PathUnknown)?;
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(suffix);
+        let tmp_path = path.with_file_name(tmp_name);
+        match std::fs::remove_file(&tmp_path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        Ok(tmp_path)
+    }
+
+    // Renames `replacement`, a pile at `tmp_path`, over the pile's file and takes
+    // its place, keeping the settings that aren't stored in the file.
+    fn replace_with(&mut self, mut replacement: Self, tmp_path: &Path) -> Result<(), LoadError> {
+        let path = self.path.clone().ok_or(LoadError::PathUnknown)?;
+        replacement.file.get_mut().unwrap().file.sync_all()?;
+        std::fs::rename(tmp_path, &path)?;
+        #[cfg(unix)]
+        if let Some(dir) = path.parent() {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            File::open(dir)?.sync_all()?;
+        }
+
+        // The sidecar describes the old file.
+        match std::fs::remove_file(index_file_path(&path)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+
+        replacement.path = Some(path);
+        replacement.validation_hook = self.validation_hook.take();
+        replacement.subscribers = std::mem::take(&mut self.subscribers);
+        replacement.insert_counters = std::mem::take(&mut self.insert_counters);
+        replacement.validation_cache = self.validation_cache.take();
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        {
+            replacement.file.get_mut().unwrap().ring = self.file.get_mut().unwrap().ring.take();
+        }
+        let access_pattern = *self.access_pattern.lock().unwrap();
+        replacement.advise(access_pattern)?;
+        *self = replacement;
+        Ok(())
+    }
+
+    /// Returns whether a blob was validated yet, without validating it.
+    ///
+    /// Blobs are validated on their first read, or by `validate_all`.
+    pub fn validation_state(&self, hash: &Hash) -> Option<ValidationState> {
+        let index = self.index.shard(hash).read().unwrap();
+        let entry = index.get(hash)?.lock().unwrap();
+        if let (ValidationState::Validated, Some(cache)) = (entry.state, &self.validation_cache) {
+            // Blobs that dropped out of the cache are validated again on their next read.
+            if !cache.is_fresh(entry.validated_at) {
+                return Some(ValidationState::Unvalidated);
+            }
+        }
+        Some(entry.state)
+    }
+
+    /// Returns the insertion time of a blob in milliseconds since the unix epoch.
+    pub fn timestamp(&self, hash: &Hash) -> Option<u64> {
+        let index = self.index.shard(hash).read().unwrap();
+        let entry = index.get(hash)?.lock().unwrap();
+        Some(entry.timestamp)
+    }
+
+    /// Reads the header of a blob back from the file, e.g. for debugging tools.
+    pub fn get_header(&self, hash: &Hash) -> Option<RawHeader> {
+        let offset = self.offset(hash)?;
+        let header_length = header_length(self.alignment);
+        let bytes = self.storage.read(offset - header_length, header_length).ok()?;
+        let (header, _) = BlobHeader::try_ref_from_prefix(&bytes[..]).ok()?;
+        Some(RawHeader {
+            magic_marker: header.magic_marker,
+            timestamp: header.timestamp.get(),
+            length: header.length.get(),
+            hash: header.hash,
+        })
+    }
+
+    /// Returns the position of a blob's bytes in the file, right after its header.
+    ///
+    /// Together with the blob length this allows other processes to read the blob
+    /// from the file directly. Compressed blobs are stored compressed,
+    /// and blobs of encrypted piles are stored encrypted.
+    pub fn offset(&self, hash: &Hash) -> Option<usize> {
+        let index = self.index.shard(hash).read().unwrap();
+        let entry = index.get(hash)?.lock().unwrap();
+        Some(entry.offset)
+    }
+
+    /// Returns the size limit of the pile, `MAX_PILE_SIZE` unless it was
+    /// loaded with a different one, e.g. by `load_with_capacity`.
+    pub const fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Returns the current length of the file, including all records.
+    pub fn used(&self) -> usize {
+        self.file.lock().unwrap().length
+    }
+
+    /// Returns the Blake3 hash of the whole file, including headers and padding,
+    /// e.g. to check whether two copies of a pile are the same.
+    ///
+    /// This reads the entire file. Blake3 is used regardless of the pile's
+    /// hash function, so the result can be compared across piles.
+    /// Fails if the file can't be read, which only happens for piles loaded with `load_no_mmap`.
+    pub fn checksum_file(&self) -> Result<Hash, std::io::Error> {
+        let mut hasher = blake3::Hasher::new();
+        self.storage.for_each_chunk(0..self.used(), |chunk| {
+            hasher.update(chunk);
+            Ok(())
+        })?;
+        Ok(hasher.finalize().into())
+    }
+
+    /// Returns how many more bytes can be appended before the pile reaches its size limit.
+    pub fn remaining_capacity(&self) -> usize {
+        self.max_size - self.used()
+    }
+
+    /// Checks whether a blob of `value_len` bytes fits into the pile,
+    /// including its header and padding.
+    ///
+    /// Compressed inserts only need room for the compressed bytes,
+    /// encrypted piles need room for a tag as well. Blobs that inserts
+    /// reject with `BlobTooLarge` never fit.
+    pub fn would_fit(&self, value_len: usize) -> bool {
+        if value_len > self.max_blob_size() {
+            return false;
+        }
+        #[cfg(feature = "encrypt")]
+        let value_len = if self.is_encrypted() {
+            value_len + ENCRYPTION_OVERHEAD
+        } else {
+            value_len
+        };
+        blob_record_length(value_len, self.alignment) <= self.remaining_capacity()
+    }
+
+    /// Returns how many bytes inserting `values` adds to a pile with the default
+    /// alignment, including headers and padding, without writing anything.
+    ///
+    /// This is an upper bound, blobs that are already stored or repeated in `values`
+    /// are only written once. Compare it to `remaining_capacity` before a large batch.
+    /// For encrypted piles or piles with another alignment, use `would_fit` instead.
+    pub fn estimate_append_size(values: &[Bytes]) -> usize {
+        values
+            .iter()
+            .map(|value| blob_record_length(value.len(), DEFAULT_ALIGNMENT))
+            .sum()
+    }
+
+    /// Reports how much of the file is used by blobs, padding and headers.
+    ///
+    /// The difference between `file_length` and the other size fields
+    /// is the space `compact` could reclaim, plus the version and branch records.
+    /// The insert counters show how much deduplication saved since the pile was loaded.
+    pub fn stats(&self) -> PileStats {
+        let file_length = self.file.lock().unwrap().length;
+        let counters = &self.insert_counters;
+        let mut stats = PileStats {
+            file_length,
+            blob_count: 0,
+            total_payload_bytes: 0,
+            total_padding_bytes: 0,
+            header_overhead_bytes: 0,
+            inserts_total: counters.inserts_total.load(Ordering::Relaxed),
+            inserts_deduplicated: counters.inserts_deduplicated.load(Ordering::Relaxed),
+            bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+            bytes_deduplicated: counters.bytes_deduplicated.load(Ordering::Relaxed),
+        };
+        for shard in self.index.shards() {
+            let index = shard.read().unwrap();
+            stats.blob_count += index.len();
+            stats.header_overhead_bytes += header_length(self.alignment) * index.len();
+            for blob in index.values() {
+                let length = blob.lock().unwrap().stored_length;
+                stats.total_payload_bytes += length;
+                stats.total_padding_bytes += padding_for_blob(length, self.alignment);
+            }
+        }
+        stats
+    }
+
+    /// Counts the stored blobs by their stored length in power of two buckets.
+    ///
+    /// Bucket 0 counts empty blobs and bucket `i` blobs of `2^(i-1)` up to `2^i - 1` bytes,
+    /// so blobs in the first 7 buckets are smaller than their 64 byte header.
+    /// The histogram ends with the last non-empty bucket.
+    pub fn size_histogram(&self) -> Vec<usize> {
+        let mut histogram = Vec::new();
+        for shard in self.index.shards() {
+            let index = shard.read().unwrap();
+            for blob in index.values() {
+                let length = blob.lock().unwrap().stored_length;
+                let bucket = (usize::BITS - length.leading_zeros()) as usize;
+                if histogram.len() <= bucket {
+                    histogram.resize(bucket + 1, 0);
+                }
+                histogram[bucket] += 1;
+            }
+        }
+        histogram
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the hashes of all stored blobs.
+    ///
+    /// The keys are snapshotted under the index read locks, so the iterator
+    /// doesn't hold them and won't observe concurrent inserts.
+    pub fn hashes(&self) -> impl Iterator<Item = Hash> {
+        let mut hashes: Vec<Hash> = Vec::new();
+        for shard in self.index.shards() {
+            let index = shard.read().unwrap();
+            hashes.extend(index.keys().copied());
+        }
+        hashes.into_iter()
+    }
+
+    /// Logically removes a blob by appending a tombstone record.
+    ///
+    /// The blob's bytes stay on disk until the pile is compacted,
+    /// this only guarantees that it won't be returned again, even after a reload.
+    /// Returns `false` if the blob wasn't present.
+    pub fn remove(&self, hash: &Hash) -> Result<bool, InsertError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly);
+        }
+
+        let mut append = self.file.lock()?;
+
+        if !self.contains(hash) {
+            return Ok(false);
+        }
+
+        let new_length = self.appended_length(append.length, header_length(self.alignment))?;
+
+        append.length = new_length;
+
+        let header = TombstoneHeader::new(*hash);
+
+        append.file.write_all(&padded_header(header.as_bytes(), self.alignment))?;
+
+        let mut index = self.index.shard(hash).write()?;
+        index.remove(hash);
+        drop(index);
+        append.appended(1, self.durability)?;
+
+        Ok(true)
+    }
+
+    /// Drops all blobs for which `f` returns `false` from the in-memory index.
+    ///
+    /// Unlike `remove` this doesn't touch the file, so the blobs are back after
+    /// the next `load`. Inserting a dropped blob again appends another copy of it.
+    /// This allows bounding the memory used by the index of a large pile.
+    ///
+    /// **Compacting afterwards loses the dropped blobs**: `compact`, `compact_in_place`
+    /// and `gc` copy the blobs in the index, so the rewritten file won't contain them.
+    pub fn retain<F: FnMut(&Hash) -> bool>(&self, mut f: F) {
+        for shard in self.index.shards() {
+            shard.write().unwrap().retain(|hash, _| f(hash));
+        }
+    }
+
+    /// Releases memory the index kept from blobs that were removed or dropped
+    /// by `retain`, e.g. after filtering a large pile down to a working set.
+    pub fn shrink_index(&self) {
+        for shard in self.index.shards() {
+            shard.write().unwrap().shrink_to_fit();
+        }
+    }
+
+    /// Adds the records that were appended to the file behind this pile's back
+    /// and returns the number of blobs that were added to the index.
+    ///
+    /// This lets a long-lived read-only pile follow a writing pile, or any other
+    /// writer that appends records in the pile format, without reopening the file.
+    /// A record that is still being written is picked up by the next call.
+    /// Blobs that are already in the index keep their entry, like they do on `load`.
+    /// Subscribers aren't notified about the added blobs.
+    pub fn rebuild_index(&self) -> Result<usize, LoadError> {
+        let mut append = self.file.lock().map_err(InsertError::from)?;
+        let old_length = append.length;
+        let file_len = append.file.metadata()?.len() as usize;
+        if file_len <= old_length {
+            return Ok(0);
+        }
+        if file_len > self.max_size {
+            return Err(LoadError::CapacityTooSmall {
+                file_len,
+                max: self.max_size,
+            });
+        }
+        self.ensure_mapped(&append.file, file_len)?;
+
+        let mut length = old_length;
+        // A pile loaded from an empty file keeps the format it assumed then,
+        // later version records are rejected by `read_record`.
+        if old_length == 0 {
+            if let Some(header) = read_version(&self.storage, file_len)? {
+                if header.version != self.version || header.alignment() != self.alignment {
+                    return Err(LoadError::HeaderError);
+                }
+                length = header_length(self.alignment);
+            }
+        }
+
+        // Parse everything first, so a corrupted record doesn't leave a partial update.
+        let mut records = Vec::new();
+        while let Some((record, next)) =
+            read_record(&self.storage, length, file_len, self.version, self.alignment)?
+        {
+            records.push((length, record));
+            length = next;
+        }
+
+        let mut added = HashSet::new();
+        for (record_offset, record) in records {
+            match record {
+                Record::Blob {
+                    hash,
+                    timestamp,
+                    length,
+                    compressed,
+                    checksum,
+                } => {
+                    let entry = IndexEntry::loaded(
+                        &self.storage,
+                        record_offset + header_length(self.alignment),
+                        length,
+                        timestamp,
+                        compressed,
+                        self.is_encrypted(),
+                        checksum,
+                    )?;
+                    self.remember_hash(&hash);
+                    let mut index = self.index.shard(&hash).write().map_err(InsertError::from)?;
+                    if let std::collections::hash_map::Entry::Vacant(vacant) = index.entry(hash) {
+                        vacant.insert(Mutex::new(entry));
+                        added.insert(hash);
+                    }
+                }
+                Record::Branch { branch_id, hash } => {
+                    self.branches.write().map_err(InsertError::from)?.insert(branch_id, hash);
+                }
+                Record::Tombstone { hash } => {
+                    self.index.shard(&hash).write().map_err(InsertError::from)?.remove(&hash);
+                    added.remove(&hash);
+                }
+                Record::Footer { length, hash } => {
+                    *self.footer.lock().map_err(InsertError::from)? = Some((length, hash));
+                }
+                Record::Head { name, hash } => {
+                    self.heads.write().map_err(InsertError::from)?.insert(name, hash);
+                }
+            }
+        }
+
+        append.length = length;
+        Ok(added.len())
+    }
+
+    pub fn commit_branch(&self, branch_id: Id, hash: Hash) -> Result<(), InsertError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly);
+        }
+
+        let mut append = self.file.lock()?;
+
+        let new_length = self.appended_length(append.length, header_length(self.alignment))?;
+
+        append.length = new_length;
+
+        let header = BranchHeader::new(branch_id, hash);
+
+        append.file.write_all(&padded_header(header.as_bytes(), self.alignment))?;
+
+        let mut branches = self.branches.write()?;
+        branches.insert(
+            branch_id,
+            hash
+        );
+        drop(branches);
+        append.appended(1, self.durability)?;
+
+        Ok(())
+    }
+
+    pub fn get_branch(&self, branch_id: Id) -> Option<Hash>{
+        let branches = self.branches.read().unwrap();
+        branches.get(&branch_id).copied()
+    }
+
+    /// Points the head called `name` at `hash` by appending a head record,
+    /// the latest record for a name wins when the pile is loaded.
+    ///
+    /// Names are at most `MAX_HEAD_NAME_LEN` bytes long and can't contain zero bytes.
+    /// Piles with a format version before 4 fail with `UnsupportedVersion`.
+    pub fn set_head(&self, name: &str, hash: Hash) -> Result<(), InsertError> {
+        let name = head_name(name).ok_or(InsertError::InvalidHeadName)?;
+        self.append_head(name, hash)
+    }
+
+    fn append_head(&self, name: [u8; MAX_HEAD_NAME_LEN], hash: Hash) -> Result<(), InsertError> {
+        if self.read_only {
+            return Err(InsertError::ReadOnly);
+        }
+        if self.version < FOOTER_AND_HEAD_VERSION {
+            return Err(InsertError::UnsupportedVersion(self.version));
+        }
+
+        let mut append = self.file.lock()?;
+
+        let new_length = self.appended_length(append.length, header_length(self.alignment))?;
+
+        append.length = new_length;
+
+        let header = HeadHeader::new(name, hash);
+
+        append.file.write_all(&padded_header(header.as_bytes(), self.alignment))?;
+
+        let mut heads = self.heads.write()?;
+        heads.insert(name, hash);
+        drop(heads);
+        append.appended(1, self.durability)?;
+
+        Ok(())
+    }
+
+    /// Returns the hash the head called `name` points to.
+    pub fn get_head(&self, name: &str) -> Option<Hash> {
+        let name = head_name(name)?;
+        let heads = self.heads.read().unwrap();
+        heads.get(&name).copied()
+    }
+
+    /// Makes the pile usable again after a thread panicked while holding one of its locks,
+    /// which otherwise makes all further inserts fail with `PoisonError`.
+    ///
+    /// A writer that panicked may have left a partial record at the end of the file,
+    /// so everything behind the last intact record is cut off. Blobs are only
+    /// indexed once their record is complete, so this never drops an indexed blob.
+    /// This is only safe if no other process appends to the file.
+    pub fn recover_from_poison(&self) -> Result<(), InsertError> {
+        let mut append = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.file.is_poisoned() && !self.read_only {
+            let file_len = append.file.metadata()?.len() as usize;
+            let scan_len = file_len.min(append.length);
+            let start = match read_version(&self.storage, scan_len) {
+                Ok(Some(_)) => header_length(self.alignment),
+                Ok(None) => 0,
+                Err(_) => return Err(InsertError::PoisonError),
+            };
+            let Ok((_, valid_length)) =
+                intact_records(&self.storage, start, scan_len, self.version, self.alignment)
+            else {
+                return Err(InsertError::PoisonError);
+            };
+            if file_len != valid_length {
+                append.file.set_len(valid_length as u64)?;
+            }
+            append.length = valid_length;
+            append.unsynced_records = 0;
+            append.last_synced_length = append.last_synced_length.min(valid_length);
+            *self.footer_hasher.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        }
+        self.file.clear_poison();
+        drop(append);
+
+        if let Storage::Mapped(mmap) = &self.storage {
+            mmap.clear_poison();
+        }
+        self.branches.clear_poison();
+        self.heads.clear_poison();
+        self.footer.clear_poison();
+        self.footer_hasher.clear_poison();
+        for shard in self.index.shards() {
+            shard.clear_poison();
+            let index = shard.read().unwrap();
+            for entry in index.values() {
+                entry.clear_poison();
+            }
+        }
+        Ok(())
+    }
+
+    /// Syncs all records appended before the call to disk.
+    ///
+    /// Inserts aren't blocked while the file is synced, records they append
+    /// in the meantime may or may not be synced, and are only guaranteed to be
+    /// after the next `flush`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn flush(&self) -> Result<(), FlushError> {
+        if self.read_only {
+            return Err(FlushError::ReadOnly);
+        }
+
+        let sync_file = self.sync_file.lock()?;
+        let (length, records) = {
+            let append = self.file.lock()?;
+            (append.length, append.unsynced_records)
+        };
+        // The file is only appended to, so syncing it covers everything up to `length`.
+        sync_file.sync_data()?;
+
+        let mut append = self.file.lock()?;
+        append.unsynced_records = append.unsynced_records.saturating_sub(records);
+        append.last_synced_length = append.last_synced_length.max(length);
+        Ok(())
+    }
+
+    /// Like `flush`, but only writes out the bytes appended since the last sync,
+    /// which is cheaper for large piles with small appends.
+    ///
+    /// On Linux this uses `sync_file_range`, which neither commits the new file length
+    /// nor flushes the drive's write cache. **Unlike `flush` it doesn't guarantee that
+    /// the records survive a crash**, it only bounds how much data is left to write,
+    /// so that the next `flush` is quick. Other platforms fall back to `flush`.
+    pub fn sync_appended(&self) -> Result<(), FlushError> {
+        if self.read_only {
+            return Err(FlushError::ReadOnly);
+        }
+
+        let mut append = self.file.lock()?;
+        if append.last_synced_length < append.length {
+            append.sync_appended()?;
+        }
+        Ok(())
+    }
+}
+
+impl<const MAX_PILE_SIZE: usize, H> Pile<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32> + Clone,
+{
+    /// Like `flush`, but first appends a footer record with the hash of all bytes
+    /// before it, which `verify_footer` checks.
+    ///
+    /// The hash state is kept between calls, so only bytes appended
+    /// since the last footer are hashed, except for the first call.
+    /// Piles with a format version before 4 fail with `UnsupportedVersion`,
+    /// readers of those versions couldn't open them anymore.
+    pub fn flush_with_footer(&self) -> Result<(), FlushError> {
+        if self.read_only {
+            return Err(FlushError::ReadOnly);
+        }
+
+        let mut append = self.file.lock()?;
+        self.append_footer(&mut append)?;
+        append.sync()?;
+        Ok(())
+    }
+
+    // Appends a footer record with the hash of all bytes before it,
+    // returns the length and hash it records.
+    fn append_footer(&self, append: &mut AppendFile) -> Result<(usize, Hash), FlushError> {
+        if self.version < FOOTER_AND_HEAD_VERSION {
+            return Err(FlushError::UnsupportedVersion(self.version));
+        }
+        let mut footer_hasher = self.footer_hasher.lock()?;
+
+        let new_length = self
+            .appended_length(append.length, header_length(self.alignment))
+            .map_err(|_| FlushError::PileTooLarge)?;
+
+        let (hashed_length, hasher) = footer_hasher.get_or_insert_with(|| (0, H::new()));
+        self.storage.for_each_chunk(*hashed_length..append.length, |chunk| {
+            Digest::update(hasher, chunk);
+            Ok(())
+        })?;
+        let hash: Hash = hasher.clone().finalize().into();
+        let footer = padded_header(
+            FooterHeader::new(append.length as u64, hash).as_bytes(),
+            self.alignment,
+        );
+
+        self.ensure_mapped(&append.file, new_length)?;
+        append.file.write_all(&footer)?;
+        Digest::update(hasher, &footer);
+        *hashed_length = new_length;
+        let footer = (append.length, hash);
+        *self.footer.lock()? = Some(footer);
+        append.length = new_length;
+        Ok(footer)
+    }
+
+    /// Like `flush_with_footer`, but also writes an index of the pile to a sidecar
+    /// file with an `.idx` suffix, which `load` reads instead of scanning all records
+    /// as long as the pile wasn't appended to since.
+    ///
+    /// The sidecar is only a cache, `load` ignores it unless the file still ends with
+    /// the footer that was appended with it, whose hash covers the whole file.
+    /// `load` doesn't hash the file to check that, `verify_footer` does.
+    /// Blobs loaded from the sidecar are still validated on their first read.
+    ///
+    /// The entries are taken from the records in the file, like a scan would,
+    /// so blobs dropped from the index with `retain` are loaded again.
+    pub fn flush_with_index(&self) -> Result<(), FlushError> {
+        if self.read_only {
+            return Err(FlushError::ReadOnly);
+        }
+        let path = self.path.as_ref().ok_or(FlushError::PathUnknown)?;
+
+        let mut append = self.file.lock()?;
+        let (footer_length, footer_hash) = self.append_footer(&mut append)?;
+        append.sync()?;
+
+        // The pile wrote these records itself, failing to parse them means corruption.
+        let malformed = |err: LoadError| std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+        let mut offset = match read_version(&self.storage, append.length).map_err(malformed)? {
+            Some(_) => header_length(self.alignment),
+            None => 0,
+        };
+        let mut blobs = HashMap::new();
+        let mut branches = HashMap::new();
+        let mut heads = HashMap::new();
+        while let Some((record, next)) =
+            read_record(&self.storage, offset, append.length, self.version, self.alignment)
+                .map_err(malformed)?
+        {
+            match record {
+                Record::Blob {
+                    hash,
+                    timestamp,
+                    length,
+                    compressed,
+                    checksum,
+                } => {
+                    let mut flags = 0;
+                    if compressed {
+                        flags |= INDEX_FILE_COMPRESSED;
+                    }
+                    if checksum.is_some() {
+                        flags |= INDEX_FILE_CHECKSUM;
+                    }
+                    let entry = IndexFileEntry {
+                        hash,
+                        offset: U64::new((offset + header_length(self.alignment)) as u64),
+                        length: U64::new(length as u64),
+                        timestamp: U64::new(timestamp),
+                        checksum: LeU32::new(checksum.unwrap_or(0)),
+                        flags,
+                        reserved: [0; 3],
+                    };
+                    // Like on `load`, a blob keeps the entry of its first record.
+                    blobs.entry(hash).or_insert(entry);
+                }
+                Record::Tombstone { hash } => {
+                    blobs.remove(&hash);
+                }
+                Record::Branch { branch_id, hash } => {
+                    branches.insert(branch_id, hash);
+                }
+                Record::Head { name, hash } => {
+                    heads.insert(name, hash);
+                }
+                Record::Footer { .. } => {}
+            }
+            offset = next;
+        }
+        let mut blobs: Vec<_> = blobs.into_values().collect();
+        blobs.sort_by_key(|blob| blob.offset.get());
+
+        let header = IndexFileHeader {
+            magic_marker: MAGIC_MARKER_INDEX_FILE,
+            file_length: U64::new(append.length as u64),
+            footer_length: U64::new(footer_length as u64),
+            footer_hash,
+            blobs: U64::new(blobs.len() as u64),
+            branches: U64::new(branches.len() as u64),
+            heads: U64::new(heads.len() as u64),
+        };
+
+        let mut buffer = header.as_bytes().to_vec();
+        for blob in &blobs {
+            buffer.extend_from_slice(blob.as_bytes());
+        }
+        for (name, hash) in branches.iter().chain(heads.iter()) {
+            let name = IndexFileName {
+                name: *name,
+                hash: *hash,
+            };
+            buffer.extend_from_slice(name.as_bytes());
+        }
+
+        // Written to a temporary file first, so that a crash never leaves half a sidecar.
+        let index_path = index_file_path(path);
+        let mut tmp_name = index_path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = index_path.with_file_name(tmp_name);
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&buffer)?;
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+        std::fs::rename(&tmp_path, &index_path)?;
+        #[cfg(unix)]
+        if let Some(dir) = index_path.parent() {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Checks the hash in the last footer record against the bytes it covers,
+    /// returns `false` if they don't match or there is no footer.
+    ///
+    /// Fails with an `IoError` if the bytes can't be read, which only happens
+    /// for piles loaded with `load_no_mmap`.
+    pub fn verify_footer(&self) -> Result<bool, LoadError> {
+        let Some((length, expected)) = *self.footer.lock().map_err(InsertError::from)? else {
+            return Ok(false);
+        };
+        let mut hasher = H::new();
+        self.storage.for_each_chunk(0..length, |chunk| {
+            Digest::update(&mut hasher, chunk);
+            Ok(())
+        })?;
+        let actual: Hash = hasher.finalize().into();
+        Ok(actual == expected)
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<const MAX_PILE_SIZE: usize, H> Pile<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32> + Send + Sync,
+{
+    /// Like `validate_all`, but hashes the blobs on rayon's thread pool.
+    ///
+    /// The validation hook may be called from several threads at once.
+    /// The invalid hashes are returned in no particular order.
+    pub fn validate_all_parallel(&self) -> Result<Vec<Hash>, GetError> {
+        use rayon::prelude::*;
+
+        let shards = self
+            .index
+            .shards()
+            .iter()
+            .map(|shard| shard.read())
+            .collect::<Result<Vec<_>, _>>()?;
+        let results: Result<Vec<Option<Hash>>, GetError> = shards
+            .par_iter()
+            .flat_map(|index| index.par_iter())
+            .map(|(hash, blob)| {
+                let mut entry = blob.lock()?;
+                Ok(match self.validate_entry(hash, &mut entry) {
+                    ValidationState::Invalid => Some(*hash),
+                    _ => None,
+                })
+            })
+            .collect();
+        Ok(results?.into_iter().flatten().collect())
+    }
+}
+
+impl<const MAX_PILE_SIZE: usize, H> Drop for Pile<MAX_PILE_SIZE, H> {
+    fn drop(&mut self) {
+        if self.durability != Durability::OnDrop || self.read_only {
+            return;
+        }
+        // Errors can't be returned from here, call `flush` to handle them.
+        match self.file.get_mut() {
+            Ok(append) if append.unsynced_records > 0 => {
+                if let Err(err) = append.file.sync_data() {
+                    log::error!("failed to sync pile on drop: {err}");
+                }
+            }
+            Ok(_) => {}
+            Err(_) => log::error!("failed to sync pile on drop: pile lock poisoned"),
+        }
+    }
+}
+
+impl<const MAX_PILE_SIZE: usize, H> Extend<Bytes> for Pile<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32>,
+{
+    fn extend<T: IntoIterator<Item = Bytes>>(&mut self, iter: T) {
+        for bytes in iter {
+            let _ = self.insert_blob(&bytes);
+        }
+    }
+}
+
+impl<const MAX_PILE_SIZE: usize, H> Extend<(Hash, Bytes)> for Pile<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32>,
+{
+    fn extend<T: IntoIterator<Item = (Hash, Bytes)>>(&mut self, iter: T) {
+        for (hash, bytes) in iter {
+            let _ = self.insert_blob_unvalidated(hash, &bytes);
+        }
+    }
+}
+
+impl<const MAX_PILE_SIZE: usize, H> IntoIterator for &Pile<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32>,
+{
+    type Item = (Hash, Bytes);
+    type IntoIter = std::vec::IntoIter<(Hash, Bytes)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<const MAX_PILE_SIZE: usize, H> Pile<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32> + Send + Sync + 'static,
+{
+    /// Moves the pile to a background thread that appends the blobs passed to
+    /// `QueuedPile::enqueue`, so that producers don't wait for the file.
+    ///
+    /// At most `capacity` blobs wait in the queue, `enqueue` blocks while it is full.
+    pub fn with_writer_thread(self, capacity: usize) -> QueuedPile<MAX_PILE_SIZE, H> {
+        let pile = Arc::new(self);
+        let (sender, receiver) = crossbeam_channel::bounded::<(Hash, Bytes)>(capacity);
+        let writer = {
+            let pile = pile.clone();
+            std::thread::spawn(move || {
+                for (hash, value) in receiver {
+                    pile.insert_blob_raw(
+                        hash,
+                        now_in_ms()?,
+                        ValidationState::Validated,
+                        &value,
+                        false,
+                    )?;
+                }
+                Ok(())
+            })
+        };
+        QueuedPile {
+            pile,
+            sender: Some(sender),
+            writer: Some(writer),
+        }
+    }
+}
+
+impl<const MAX_PILE_SIZE: usize, H> QueuedPile<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32>,
+{
+    /// Hashes the blob and queues it for the writer thread, returning its hash
+    /// right away. Blocks while the queue is full.
+    ///
+    /// The blob can't be read back before the writer thread has appended it.
+    /// If appending a blob fails the writer thread stops, and later calls return
+    /// `WriterStopped`, see `shutdown` for the error.
+    pub fn enqueue(&self, value: Bytes) -> Result<Hash, InsertError> {
+        if self.pile.read_only {
+            return Err(InsertError::ReadOnly);
+        }
+        self.pile.check_blob_size(value.len())?;
+        let hash: Hash = H::digest(&value).into();
+
+        let sender = self.sender.as_ref().expect("only taken on shutdown");
+        sender
+            .send((hash, value))
+            .map_err(|_| InsertError::WriterStopped)?;
+
+        Ok(hash)
+    }
+
+    /// The pile the writer thread appends to, for reading.
+    pub fn pile(&self) -> &Pile<MAX_PILE_SIZE, H> {
+        &self.pile
+    }
+
+    /// Waits for the writer thread to append all queued blobs, syncs them to disk
+    /// like `Pile::flush` and returns the pile, or the error that stopped the writer
+    /// thread.
+    pub fn shutdown(mut self) -> Result<Pile<MAX_PILE_SIZE, H>, InsertError> {
+        self.stop_writer()?;
+        let pile = self.pile.clone();
+        drop(self);
+        let pile = Arc::into_inner(pile).expect("the writer thread has finished");
+        if !pile.read_only {
+            pile.flush().map_err(|err| match err {
+                FlushError::IoError(err) => InsertError::IoError(err),
+                // Flushing fails with I/O errors and poisoned locks only.
+                _ => InsertError::PoisonError,
+            })?;
+        }
+        Ok(pile)
+    }
+
+    fn stop_writer(&mut self) -> Result<(), InsertError> {
+        drop(self.sender.take());
+        match self.writer.take() {
+            Some(writer) => writer
+                .join()
+                .unwrap_or_else(|err| std::panic::resume_unwind(err)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<const MAX_PILE_SIZE: usize, H> Drop for QueuedPile<MAX_PILE_SIZE, H> {
+    fn drop(&mut self) {
+        // Errors can't be returned from here, call `shutdown` to handle them.
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            match writer.join() {
+                Ok(Err(err)) => log::error!("failed to append queued blobs: {err}"),
+                Ok(Ok(())) => {}
+                Err(_) => log::error!("pile writer thread panicked"),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<const MAX_PILE_SIZE: usize, H> Pile<MAX_PILE_SIZE, H>
+where
+    H: Digest<OutputSize = U32> + Send + Sync + 'static,
+{
+    /// Like `insert_blob`, but hashes and writes the blob on tokio's blocking thread pool.
+    pub async fn insert_blob_async(self: &Arc<Self>, value: &Bytes) -> Result<Hash, InsertError> {
+        let pile = self.clone();
+        let value = value.clone();
+        tokio::task::spawn_blocking(move || pile.insert_blob(&value))
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+
+    /// Like `flush`, but syncs the file on tokio's blocking thread pool.
+    pub async fn flush_async(self: &Arc<Self>) -> Result<(), FlushError> {
+        let pile = self.clone();
+        tokio::task::spawn_blocking(move || pile.flush())
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::RngCore;
+
+    const MAX_PILE_SIZE: usize = 1 << 20;
+
+    // Loads an empty pile from a temporary directory, which is removed when it is dropped.
+    fn temp_pile() -> (tempfile::TempDir, PathBuf, Pile<MAX_PILE_SIZE>) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let pile = Pile::load(&tmp_pile).unwrap();
+        (tmp_dir, tmp_pile, pile)
+    }
+
+    // Stores two blobs of 100 bytes and flips a bit of the second one in the file.
+    fn corrupted_pile() -> (tempfile::TempDir, PathBuf, Hash, Hash) {
+        let (tmp_dir, tmp_pile, pile) = temp_pile();
+        let intact = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let corrupted = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        drop(pile);
+
+        let mut file_bytes = std::fs::read(&tmp_pile).unwrap();
+        file_bytes[64 + (64 + 128) + 64] ^= 1;
+        std::fs::write(&tmp_pile, &file_bytes).unwrap();
+        (tmp_dir, tmp_pile, intact, corrupted)
+    }
+
+    #[test]
+    fn load() {
+        const RECORD_LEN: usize = 1 << 10; // 1k
+        const RECORD_COUNT: usize = 1 << 20; // 1M
+        const MAX_PILE_SIZE: usize = 1 << 30; // 100GB
+
+        let mut rng = rand::thread_rng();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+
+        (0..RECORD_COUNT).for_each(|_| {
+            let mut record = Vec::with_capacity(RECORD_LEN);
+            rng.fill_bytes(&mut record);
+
+            let data = Bytes::from_source(record);
+            pile.insert_blob(&data).unwrap();
+        });
+
+        pile.flush().unwrap();
+
+        drop(pile);
+
+        let _pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+    }
+
+    #[test]
+    fn clock_before_the_epoch_is_an_error() {
+        let second = std::time::Duration::from_millis(1000);
+        assert_eq!(ms_since_epoch(UNIX_EPOCH + second).unwrap(), 1000);
+
+        let err = InsertError::from(ms_since_epoch(UNIX_EPOCH - second).unwrap_err());
+        assert!(matches!(err, InsertError::ClockError(_)));
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn sha256_addressed_pile() {
+        use sha2::{Digest, Sha256};
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let pile: Pile<MAX_PILE_SIZE, Sha256> = Pile::load(&tmp_pile).unwrap();
+        let hash = pile.insert_slice(&[1u8; 100]).unwrap();
+        assert_eq!(hash, <[u8; 32]>::from(Sha256::digest([1u8; 100])));
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE, Sha256> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &[1u8; 100][..]);
+        drop(pile);
+
+        // The file doesn't record the hasher, so reading it with another one fails validation.
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.validate_all().unwrap(), vec![hash]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_inserts_and_flushes() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let pile = Arc::new(pile);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let hash = runtime.block_on(async {
+            let hash = pile.insert_blob_async(&Bytes::from_source(vec![1u8; 100])).await.unwrap();
+            pile.flush_async().await.unwrap();
+            hash
+        });
+        assert_eq!(pile.file.lock().unwrap().unsynced_records, 0);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &[1u8; 100][..]);
+    }
+
+    #[test]
+    fn open_read_only_doesnt_write() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::open_read_only(&tmp_pile),
+            Err(LoadError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound
+        ));
+        assert!(!tmp_pile.exists());
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let hash = pile.insert_slice(&[1u8; 100]).unwrap();
+        drop(pile);
+        let file_bytes = std::fs::read(&tmp_pile).unwrap();
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::open_read_only(&tmp_pile).unwrap();
+        assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &[1u8; 100][..]);
+        assert!(matches!(pile.insert_slice(&[2u8; 100]), Err(InsertError::ReadOnly)));
+        assert!(matches!(pile.remove(&hash), Err(InsertError::ReadOnly)));
+        assert!(matches!(pile.commit_branch([1; 16], hash), Err(InsertError::ReadOnly)));
+        assert!(matches!(pile.flush(), Err(FlushError::ReadOnly)));
+        drop(pile);
+        assert_eq!(std::fs::read(&tmp_pile).unwrap(), file_bytes);
+    }
+
+    #[test]
+    fn aligned_blobs_have_no_padding() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+
+        let blob_64 = Bytes::from_source(vec![1u8; 64]);
+        let blob_128 = Bytes::from_source(vec![2u8; 128]);
+        let hash_64 = pile.insert_blob(&blob_64).unwrap();
+        let hash_128 = pile.insert_blob(&blob_128).unwrap();
+        pile.flush().unwrap();
+        drop(pile);
+
+        let file_len = std::fs::metadata(&tmp_pile).unwrap().len();
+        assert_eq!(file_len, 64 + (64 + 64) + (64 + 128));
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(&*pile.get_blob(&hash_64).unwrap().unwrap(), &blob_64[..]);
+        assert_eq!(&*pile.get_blob(&hash_128).unwrap().unwrap(), &blob_128[..]);
+        assert_eq!(pile.offset(&hash_64), Some(64 + 64));
+        assert_eq!(pile.offset(&hash_128), Some(64 + (64 + 64) + 64));
+    }
+
+    #[test]
+    fn compact_in_place_keeps_old_bytes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let mut pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let kept = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let removed = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        let old_bytes = pile.get_blob(&removed).unwrap().unwrap();
+        pile.remove(&removed).unwrap();
+        let file_len = std::fs::metadata(&tmp_pile).unwrap().len();
+
+        pile.compact_in_place().unwrap();
+        assert!(std::fs::metadata(&tmp_pile).unwrap().len() < file_len);
+        assert!(!tmp_dir.path().join("test.pile.compact").exists());
+        assert_eq!(&*old_bytes, &[2u8; 100][..]);
+        assert_eq!(&*pile.get_blob(&kept).unwrap().unwrap(), &[1u8; 100][..]);
+        assert!(!pile.contains(&removed));
+        pile.insert_blob(&Bytes::from_source(vec![3u8; 100])).unwrap();
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.len(), 2);
+        drop(pile);
+
+        let file = OpenOptions::new().read(true).append(true).open(&tmp_pile).unwrap();
+        let mut pile: Pile<MAX_PILE_SIZE> = Pile::from_file(file, MAX_PILE_SIZE).unwrap();
+        assert!(matches!(pile.compact_in_place(), Err(LoadError::PathUnknown)));
+    }
+
+    #[test]
+    fn compaction_keeps_the_load_options() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let kept = pile.insert_slice(&[1u8; 100]).unwrap();
+        let removed = pile.insert_slice(&[2u8; 100]).unwrap();
+        pile.remove(&removed).unwrap();
+        drop(pile);
+
+        let mut pile: Pile<MAX_PILE_SIZE> = Pile::load_no_mmap(&tmp_pile).unwrap();
+        pile.compact_in_place().unwrap();
+        assert!(matches!(pile.storage, Storage::Read(_)));
+        assert!(!pile.contains(&removed));
+        pile.truncate_to(&kept).unwrap();
+        assert!(matches!(pile.storage, Storage::Read(_)));
+        assert_eq!(&*pile.get_blob(&kept).unwrap().unwrap(), &[1u8; 100][..]);
+    }
+
+    #[test]
+    fn writer_thread_appends_queued_blobs() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let queued = pile.with_writer_thread(2);
+        let hashes: Vec<Hash> = (0..10u8)
+            .map(|i| queued.enqueue(Bytes::from_source(vec![i; 100])).unwrap())
+            .collect();
+        assert!(matches!(
+            queued.enqueue(Bytes::from_source(vec![0u8; MAX_PILE_SIZE])),
+            Err(InsertError::BlobTooLarge { .. })
+        ));
+
+        let pile = queued.shutdown().unwrap();
+        for (i, hash) in hashes.iter().enumerate() {
+            assert_eq!(&*pile.get_blob(hash).unwrap().unwrap(), &[i as u8; 100][..]);
+        }
+
+        // Blobs only fit in the pile until it is full, the first one that doesn't
+        // stops the writer. It is the last one queued, so every enqueue succeeds.
+        let fitting = pile.remaining_capacity() / 192;
+        let queued = pile.with_writer_thread(2);
+        for i in 0..=fitting {
+            let value = Bytes::from_source((i as u64).to_le_bytes().repeat(12));
+            queued.enqueue(value).unwrap();
+        }
+        assert!(matches!(queued.shutdown(), Err(InsertError::PileTooLarge)));
+    }
+
+    #[test]
+    fn share_pile_between_threads() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let pile: Arc<Pile<MAX_PILE_SIZE>> = Arc::new(Pile::load(&tmp_pile).unwrap());
+        let shared = pile.insert_blob(&Bytes::from_source(vec![0u8; 100])).unwrap();
+
+        let threads: Vec<_> = (1..=4u8)
+            .map(|i| {
+                let pile = Arc::clone(&pile);
+                std::thread::spawn(move || {
+                    for len in 0..16 {
+                        let value = vec![i; len];
+                        let hash = pile.insert_blob(&Bytes::from_source(value.clone())).unwrap();
+                        assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &value[..]);
+                        assert_eq!(&*pile.get_blob(&shared).unwrap().unwrap(), &[0u8; 100][..]);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Every thread inserted the same empty blob.
+        assert_eq!(pile.len(), 1 + 1 + 4 * 15);
+    }
+
+    #[test]
+    fn insert_at_returns_record_offsets() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let (first, offset) = pile.insert_at(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        assert_eq!(offset, 64);
+        let (_, offset) = pile.insert_at(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        assert_eq!(offset, 64 + 64 + 128);
+        assert_eq!(pile.insert_at(&Bytes::from_source(vec![1u8; 100])).unwrap(), (first, 64));
+        assert_eq!(pile.offset(&first), Some(64 + 64));
+    }
+
+    #[test]
+    fn gc_keeps_reachable_blobs() {
+        // Blobs are lists of the hashes they reference.
+        fn refs(bytes: &[u8]) -> Vec<Hash> {
+            bytes.chunks_exact(32).map(|c| c.try_into().unwrap()).collect()
+        }
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let mut pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let leaf = pile.insert_blob(&Bytes::from_source(vec![7u8; 32])).unwrap();
+        let node = pile.insert_blob(&Bytes::from_source(leaf.to_vec())).unwrap();
+        let root = pile.insert_blob(&Bytes::from_source([node, node].concat())).unwrap();
+        let branch = pile.insert_blob(&Bytes::from_source(vec![8u8; 32])).unwrap();
+        pile.commit_branch([1; 16], branch).unwrap();
+        let garbage = pile.insert_blob(&Bytes::from_source(leaf.to_vec().repeat(3))).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![9u8; 100])).unwrap();
+        let length = pile.used();
+
+        let report = pile.gc(&[root], refs).unwrap();
+        assert_eq!(report.reachable, 4);
+        assert_eq!(report.collected, 2);
+        assert_eq!(report.reclaimed_bytes, length - pile.used());
+        for hash in [leaf, node, root, branch] {
+            assert!(pile.get_blob(&hash).unwrap().is_some());
+        }
+        assert!(!pile.contains(&garbage));
+        assert_eq!(pile.get_branch([1; 16]), Some(branch));
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.len(), 4);
+    }
+
+    #[test]
+    fn blob_refs_fetch_from_their_pile() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let blob = Bytes::from_source(vec![1u8; 100]);
+        let hash = pile.insert_blob(&blob).unwrap();
+
+        let blob_ref = pile.get(&hash).unwrap();
+        assert_eq!(blob_ref.hash(), hash);
+        assert_eq!(blob_ref.fetch().unwrap(), blob);
+        assert!(pile.get(&[0; 32]).is_none());
+
+        pile.remove(&hash).unwrap();
+        assert!(matches!(blob_ref.fetch(), Err(GetError::NotFound)));
+    }
+
+    #[test]
+    fn open_existing_and_create_new() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::open_existing(&tmp_pile),
+            Err(LoadError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound
+        ));
+        assert!(!tmp_pile.exists());
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::create_new(&tmp_pile).unwrap();
+        let hash = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        drop(pile);
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::create_new(&tmp_pile),
+            Err(LoadError::IoError(err)) if err.kind() == std::io::ErrorKind::AlreadyExists
+        ));
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::open_existing(&tmp_pile).unwrap();
+        assert!(pile.contains(&hash));
+    }
+
+    #[test]
+    fn get_or_insert_with_checks_the_hash() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let blob = Bytes::from_source(vec![1u8; 100]);
+        let hash: Hash = Blake3::digest(&blob).into();
+
+        assert!(matches!(
+            pile.get_or_insert_with(&hash, || Bytes::from_source(vec![2u8; 100])),
+            Err(GetError::ValidationError { .. })
+        ));
+        assert!(!pile.contains(&hash));
+
+        assert_eq!(pile.get_or_insert_with(&hash, || blob.clone()).unwrap(), blob);
+        assert!(pile.contains(&hash));
+        let stored = pile.get_or_insert_with(&hash, || unreachable!()).unwrap();
+        assert_eq!(stored, blob);
+    }
+
+    #[test]
+    fn subscribers_see_new_blobs() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let receiver = pile.subscribe();
+        let dropped = pile.subscribe();
+        drop(dropped);
+
+        let first = pile.insert_slice(&[1u8; 100]).unwrap();
+        pile.insert_slice(&[1u8; 100]).unwrap();
+        let batch = pile
+            .insert_many(&[Bytes::from_source(vec![2u8; 10]), Bytes::from_source(vec![3u8; 10])])
+            .unwrap();
+        let streamed = pile.insert_reader(&[4u8; 10][..]).unwrap();
+
+        let received: Vec<Hash> = receiver.try_iter().collect();
+        assert_eq!(received, vec![first, batch[0], batch[1], streamed]);
+        assert_eq!(pile.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retain_only_drops_from_the_index() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let kept = pile.insert_slice(&[1u8; 100]).unwrap();
+        let dropped = pile.insert_slice(&[2u8; 100]).unwrap();
+        let used = pile.used();
+
+        pile.retain(|hash| *hash == kept);
+        assert!(pile.contains(&kept));
+        assert!(!pile.contains(&dropped));
+        assert_eq!(pile.len(), 1);
+        assert_eq!(pile.used(), used);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.contains(&dropped));
+    }
+
+    #[test]
+    fn shrink_index_releases_capacity() {
+        let capacity = |pile: &Pile<MAX_PILE_SIZE>| -> usize {
+            pile.index.shards().iter().map(|shard| shard.read().unwrap().capacity()).sum()
+        };
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let values: Vec<Bytes> = (0..1000u32)
+            .map(|i| Bytes::from_source(i.to_le_bytes().to_vec()))
+            .collect();
+        let hashes = pile.insert_many(&values).unwrap();
+
+        pile.retain(|hash| *hash == hashes[0]);
+        let grown = capacity(&pile);
+        pile.shrink_index();
+        assert!(capacity(&pile) < grown);
+        assert!(pile.contains(&hashes[0]));
+    }
+
+    #[test]
+    fn get_owned_copies_the_blob() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let hash = pile.insert_slice(&[1u8; 100]).unwrap();
+
+        let owned = pile.get_owned(&hash).unwrap().unwrap();
+        assert_ne!(owned.as_ptr(), pile.get_blob(&hash).unwrap().unwrap().as_ptr());
+        assert!(pile.get_owned(&[0; 32]).unwrap().is_none());
+        drop(pile);
+        assert_eq!(&*owned, &[1u8; 100][..]);
+    }
+
+    #[test]
+    fn reopen_with_smaller_capacity() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        pile.insert_slice(&[1u8; 100]).unwrap();
+        drop(pile);
+
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load_with_capacity(&tmp_pile, 128),
+            Err(LoadError::CapacityTooSmall { file_len: 256, max: 128 })
+        ));
+        let pile = Pile::<MAX_PILE_SIZE>::load_with_capacity(&tmp_pile, 1 << 30).unwrap();
+        assert_eq!(pile.len(), 1);
+    }
+
+    #[test]
+    fn misaligned_capacity_is_rejected() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        assert!(matches!(
+            Pile::<1000>::load(&tmp_pile),
+            Err(LoadError::MisalignedCapacity { max: 1000, alignment: 64 })
+        ));
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load_with_capacity(&tmp_pile, 1000),
+            Err(LoadError::MisalignedCapacity { max: 1000, alignment: 64 })
+        ));
+
+        // The limit has to be a multiple of the pile's own alignment.
+        let aligned = tmp_dir.path().join("aligned.pile");
+        assert!(matches!(
+            Pile::<{ 64 * 3 }>::load_with_alignment(&aligned, 128),
+            Err(LoadError::MisalignedCapacity { max: 192, alignment: 128 })
+        ));
+        Pile::<{ 128 * 3 }>::load_with_alignment(&aligned, 128).unwrap();
+    }
+
+    #[test]
+    fn checksum_file_covers_the_whole_file() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        pile.insert_slice(&[1u8; 100]).unwrap();
+        let checksum = pile.checksum_file().unwrap();
+        assert_eq!(checksum, *blake3::hash(&std::fs::read(&tmp_pile).unwrap()).as_bytes());
+
+        pile.commit_branch([1; 16], [0; 32]).unwrap();
+        assert_ne!(pile.checksum_file().unwrap(), checksum);
+    }
+
+    #[test]
+    fn flush_while_inserting() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..100u32 {
+                    pile.insert_slice(&i.to_le_bytes()).unwrap();
+                }
+            });
+            for _ in 0..20 {
+                pile.flush().unwrap();
+            }
+        });
+        pile.flush().unwrap();
+        assert_eq!(pile.file.lock().unwrap().unsynced_records, 0);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.len(), 100);
+    }
+
+    #[test]
+    fn durability_policies_sync_records() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+
+        let pile: Pile<MAX_PILE_SIZE> =
+            Pile::load_with_durability(&tmp_pile, Durability::None).unwrap();
+        pile.insert_slice(b"a").unwrap();
+        pile.commit_branch([1; 16], [0; 32]).unwrap();
+        assert_eq!(pile.file.lock().unwrap().unsynced_records, 2);
+        pile.flush().unwrap();
+        assert_eq!(pile.file.lock().unwrap().unsynced_records, 0);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> =
+            Pile::load_with_durability(&tmp_pile, Durability::EverySync(3)).unwrap();
+        let hash = pile.insert_slice(b"b").unwrap();
+        pile.set_head("main", hash).unwrap();
+        assert_eq!(pile.file.lock().unwrap().unsynced_records, 2);
+        pile.remove(&hash).unwrap();
+        assert_eq!(pile.file.lock().unwrap().unsynced_records, 0);
+        assert!(!pile.contains(&hash));
+        pile.insert_slice(b"c").unwrap();
+        assert_eq!(pile.file.lock().unwrap().unsynced_records, 1);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> =
+            Pile::load_with_durability(&tmp_pile, Durability::OnDrop).unwrap();
+        assert_eq!(pile.len(), 2);
+        pile.insert_slice(b"d").unwrap();
+        assert_eq!(pile.file.lock().unwrap().unsynced_records, 1);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.len(), 3);
+        assert_eq!(pile.get_head("main"), Some(hash));
+        assert_eq!(pile.get_branch([1; 16]), Some([0; 32]));
+    }
+
+    #[test]
+    fn iterate_by_timestamp() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let hashes: Vec<Hash> = [1000, 2000, 3000]
+            .into_iter()
+            .map(|timestamp| {
+                let blob = Bytes::from_source((timestamp as u32).to_le_bytes().to_vec());
+                pile.insert_with_timestamp(&blob, timestamp).unwrap()
+            })
+            .collect();
+
+        let sorted = |iter: std::vec::IntoIter<(Hash, Bytes)>| {
+            let mut found: Vec<Hash> = iter.map(|(hash, _)| hash).collect();
+            found.sort();
+            found
+        };
+        let mut expected = hashes[1..].to_vec();
+        expected.sort();
+        assert_eq!(sorted(pile.iter_since(2000)), expected);
+        assert_eq!(sorted(pile.iter_between(1000, 3000)).len(), 2);
+        assert_eq!(sorted(pile.iter_between(1500, 2500)), vec![hashes[1]]);
+        assert!(pile.iter_since(3001).next().is_none());
+    }
+
+    #[test]
+    fn load_without_mapping() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let first = pile.insert_slice(&[1u8; 100]).unwrap();
+        pile.commit_branch([1; 16], first).unwrap();
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load_no_mmap(&tmp_pile).unwrap();
+        assert!(matches!(pile.storage, Storage::Read(_)));
+        assert_eq!(&*pile.get_blob(&first).unwrap().unwrap(), &[1u8; 100][..]);
+        let second = pile.insert_slice(&[2u8; 100]).unwrap();
+        assert_eq!(&*pile.get_blob(&second).unwrap().unwrap(), &[2u8; 100][..]);
+        assert_eq!(pile.get_branch([1; 16]), Some(first));
+        assert_eq!(pile.iter_in_order().count(), 2);
+        pile.flush_with_footer().unwrap();
+        assert!(pile.verify_footer().unwrap());
+        let checksum = pile.checksum_file().unwrap();
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.checksum_file().unwrap(), checksum);
+        assert_eq!(pile.len(), 2);
+    }
+
+    #[test]
+    fn insert_slices_and_vecs() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let from_slice = pile.insert_slice(&[1u8; 100]).unwrap();
+        let from_vec = pile.insert_vec(vec![1u8; 100]).unwrap();
+        assert_eq!(from_slice, from_vec);
+        assert_eq!(pile.len(), 1);
+        assert_eq!(&*pile.get_blob(&from_vec).unwrap().unwrap(), &[1u8; 100][..]);
+    }
+
+    #[test]
+    fn custom_alignment() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load_with_alignment(&tmp_pile, 100),
+            Err(LoadError::InvalidAlignment(100))
+        ));
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load_with_alignment(&tmp_pile, 4096).unwrap();
+        let first = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.commit_branch([1; 16], first).unwrap();
+        let second = pile.insert_blob(&Bytes::from_source(vec![2u8; 5000])).unwrap();
+        pile.flush_with_footer().unwrap();
+        drop(pile);
+
+        assert_eq!(std::fs::metadata(&tmp_pile).unwrap().len(), 4096 * 8);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.alignment(), 4096);
+        assert!(pile.verify_footer().unwrap());
+        assert_eq!(pile.offset(&first), Some(2 * 4096));
+        assert_eq!(pile.offset(&second), Some(5 * 4096));
+        assert_eq!(&*pile.get_blob(&second).unwrap().unwrap(), &[2u8; 5000][..]);
+        assert_eq!(pile.get_branch([1; 16]), Some(first));
+        drop(pile);
+
+        let tmp_pile = tmp_dir.path().join("small.pile");
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load_with_alignment(&tmp_pile, 16).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        assert_eq!(pile.used(), 64 + 64 + 112);
+        drop(pile);
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.validate_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn empty_blob_round_trip() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let hash = pile.insert_blob(&Bytes::empty()).unwrap();
+        assert_eq!(hash, <[u8; 32]>::from(Blake3::digest([])));
+        let other = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.flush().unwrap();
+        drop(pile);
+
+        assert_eq!(std::fs::metadata(&tmp_pile).unwrap().len(), 64 + 64 + (64 + 128));
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.get_blob(&hash).unwrap().unwrap().is_empty());
+        assert_eq!(&*pile.get_blob(&other).unwrap().unwrap(), &[1u8; 100][..]);
+    }
+
+    #[test]
+    fn insert_from_reader() {
+        const MAX_PILE_SIZE: usize = 1 << 22;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+
+        let blob: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+        let hash = pile.insert_reader(&blob[..]).unwrap();
+        assert_eq!(hash, <[u8; 32]>::from(Blake3::digest(&blob)));
+        assert_eq!(pile.insert_reader(&blob[..]).unwrap(), hash);
+        let file_len = std::fs::metadata(&tmp_pile).unwrap().len();
+
+        let too_large = vec![0u8; MAX_PILE_SIZE];
+        assert!(matches!(
+            pile.insert_reader(&too_large[..]),
+            Err(InsertError::PileTooLarge)
+        ));
+        assert_eq!(std::fs::metadata(&tmp_pile).unwrap().len(), file_len);
+        assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &blob[..]);
+        // The blobs were spooled next to the pile, and the temporary files are gone.
+        assert_eq!(std::fs::read_dir(tmp_dir.path()).unwrap().count(), 1);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(&*pile.get_fast_checked(&hash).unwrap().unwrap(), &blob[..]);
+    }
+
+    #[test]
+    fn lenient_load_skips_truncated_record() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let kept = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let lost = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        pile.flush().unwrap();
+        drop(pile);
+
+        let file = OpenOptions::new().write(true).open(&tmp_pile).unwrap();
+        file.set_len(64 + (64 + 128) + 128).unwrap();
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load(&tmp_pile),
+            Err(LoadError::UnexpectedEndOfFile {
+                offset: 256,
+                missing: 64,
+            })
+        ));
+        file.set_len(64 + (64 + 128) + 100).unwrap();
+        drop(file);
+
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load(&tmp_pile),
+            Err(LoadError::FileLengthError {
+                file_length: 356,
+                alignment: 64,
+            })
+        ));
+
+        let (pile, report) = Pile::<MAX_PILE_SIZE>::load_lenient(&tmp_pile, true).unwrap();
+        assert_eq!(report.ignored_bytes, 100);
+        assert!(report.truncated);
+        assert!(pile.contains(&kept));
+        assert!(!pile.contains(&lost));
+        pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        pile.flush().unwrap();
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.contains(&lost));
+    }
+
+    #[test]
+    fn lenient_load_skips_zeroed_tail() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let kept = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let length = pile.used();
+        drop(pile);
+
+        let file = OpenOptions::new().write(true).open(&tmp_pile).unwrap();
+        file.set_len(length as u64 + 256).unwrap();
+        drop(file);
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load(&tmp_pile),
+            Err(LoadError::MagicMarkerError)
+        ));
+
+        let (pile, report) = Pile::<MAX_PILE_SIZE>::load_lenient(&tmp_pile, false).unwrap();
+        assert_eq!(report.ignored_bytes, 256);
+        assert!(!report.truncated);
+        assert!(pile.contains(&kept));
+        assert!(matches!(
+            pile.insert_blob(&Bytes::from_source(vec![2u8; 100])),
+            Err(InsertError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn repair_cuts_off_corrupted_tail() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let kept = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.flush().unwrap();
+        drop(pile);
+
+        let mut file = OpenOptions::new().append(true).open(&tmp_pile).unwrap();
+        file.write_all(&[0xFF; 128]).unwrap();
+        drop(file);
+
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load(&tmp_pile),
+            Err(LoadError::MagicMarkerError)
+        ));
+
+        let report = Pile::<MAX_PILE_SIZE>::repair(&tmp_pile).unwrap();
+        assert_eq!(
+            report,
+            RepairReport {
+                records_kept: 1,
+                discarded_bytes: 128,
+            }
+        );
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.contains(&kept));
+    }
+
+    #[test]
+    fn duplicate_inserts_are_skipped() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+
+        let blob = Bytes::from_source(vec![1u8; 100]);
+        let hash = pile.insert_blob(&blob).unwrap();
+        assert_eq!(pile.insert_blob(&blob).unwrap(), hash);
+        pile.insert_blob_unvalidated(hash, &blob).unwrap();
+        pile.insert_many(&[blob.clone(), blob.clone()]).unwrap();
+        pile.flush().unwrap();
+
+        assert_eq!(std::fs::metadata(&tmp_pile).unwrap().len(), 64 + (64 + 128));
+    }
+
+    #[test]
+    fn insert_if_absent_reports_new_blobs() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let blob = Bytes::from_source(vec![1u8; 100]);
+        let (hash, added) = pile.insert_if_absent(&blob).unwrap();
+        assert!(added);
+        assert!(pile.contains(&hash));
+        assert_eq!(pile.insert_if_absent(&blob).unwrap(), (hash, false));
+
+        let used = pile.used();
+        let other = Bytes::from_source(vec![2u8; 100]);
+        assert_eq!(pile.insert_blob(&other).unwrap(), pile.insert_if_absent(&other).unwrap().0);
+        assert_eq!(pile.used(), used + (64 + 128));
+    }
+
+    #[test]
+    fn blob_header_is_little_endian() {
+        let header = BlobHeader::new(0x0102030405060708, 0x1122334455667788, [0xAB; 32]);
+        let bytes = header.as_bytes();
+
+        assert_eq!(bytes.len(), 64);
+        assert_eq!(&bytes[0..16], &MAGIC_MARKER_BLOB);
+        assert_eq!(&bytes[16..24], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(&bytes[24..32], &[0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(&bytes[32..64], &[0xAB; 32]);
+
+        let parsed = BlobHeader::try_read_from_bytes(bytes).unwrap();
+        assert_eq!(parsed.timestamp.get(), 0x0102030405060708);
+        assert_eq!(parsed.length.get(), 0x1122334455667788);
+    }
+
+    #[test]
+    fn format_versions() {
+        let (tmp_dir, _, pile) = temp_pile();
+        assert_eq!(pile.format_version(), FORMAT_VERSION);
+        drop(pile);
+
+        let v0_pile = tmp_dir.path().join("v0.pile");
+        let blob = [3u8; 64];
+        let hash: Hash = Blake3::digest(blob).into();
+        let mut v0_bytes = Vec::new();
+        v0_bytes.extend_from_slice(BlobHeader::new(0, 64, hash).as_bytes());
+        v0_bytes.extend_from_slice(&blob);
+        v0_bytes.extend_from_slice(&[0; 64]);
+        std::fs::write(&v0_pile, &v0_bytes).unwrap();
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&v0_pile).unwrap();
+        assert_eq!(pile.format_version(), 0);
+        assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &blob[..]);
+        drop(pile);
+
+        let future_pile = tmp_dir.path().join("future.pile");
+        std::fs::write(&future_pile, VersionHeader::new(FORMAT_VERSION + 1).as_bytes()).unwrap();
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load(&future_pile),
+            Err(LoadError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn old_formats_refuse_footers_and_heads() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let v3_pile = tmp_dir.path().join("v3.pile");
+        std::fs::write(&v3_pile, VersionHeader::new(3).as_bytes()).unwrap();
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&v3_pile).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        assert!(matches!(pile.flush_with_footer(), Err(FlushError::UnsupportedVersion(3))));
+        assert!(!pile.verify_footer().unwrap());
+        assert!(matches!(
+            pile.set_head("main", [0; 32]),
+            Err(InsertError::UnsupportedVersion(3))
+        ));
+        assert_eq!(pile.get_head("main"), None);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&v3_pile).unwrap();
+        assert_eq!(pile.format_version(), 3);
+        assert_eq!(pile.len(), 1);
+    }
+
+    #[test]
+    fn validate_rechecks_validated_blobs() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let hash = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.flush().unwrap();
+        assert!(pile.validate(&hash).unwrap());
+
+        // The mapping sees changes to the file, even those behind the pile's back.
+        let mut file = OpenOptions::new().write(true).open(&tmp_pile).unwrap();
+        file.seek(SeekFrom::Start(64 + 64)).unwrap();
+        file.write_all(&[2]).unwrap();
+        assert_eq!(pile.validation_state(&hash), Some(ValidationState::Validated));
+        assert!(!pile.validate(&hash).unwrap());
+        assert_eq!(pile.validation_state(&hash), Some(ValidationState::Invalid));
+        assert!(matches!(pile.validate(&[0; 32]), Err(GetError::NotFound)));
+    }
+
+    #[test]
+    fn checksums_detect_corruption() {
+        let (_tmp_dir, tmp_pile, intact, corrupted) = corrupted_pile();
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(&*pile.get_fast_checked(&intact).unwrap().unwrap(), &[1u8; 100][..]);
+        assert!(matches!(
+            pile.get_fast_checked(&corrupted),
+            Err(GetError::ValidationError { .. })
+        ));
+        assert_eq!(pile.get_fast_checked(&[0; 32]).unwrap(), None);
+    }
+
+    #[test]
+    fn validation_hook_sees_results() {
+        let (_tmp_dir, tmp_pile, intact, corrupted) = corrupted_pile();
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap().with_validation_hook({
+            let results = results.clone();
+            move |hash, valid| results.lock().unwrap().push((hash, valid))
+        });
+        for _ in 0..2 {
+            assert!(pile.get_blob(&intact).unwrap().is_some());
+            assert!(matches!(
+                pile.get_blob(&corrupted),
+                Err(GetError::ValidationError { .. })
+            ));
+        }
+        // Blobs are only reported when they are validated, which is on their first read.
+        assert_eq!(*results.lock().unwrap(), vec![(intact, true), (corrupted, false)]);
+
+        assert_eq!(pile.validate_all().unwrap(), vec![corrupted]);
+        assert_eq!(results.lock().unwrap().len(), 2);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_validation_matches_serial_validation() {
+        let (_tmp_dir, tmp_pile, intact, corrupted) = corrupted_pile();
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.validate_all_parallel().unwrap(), vec![corrupted]);
+        assert_eq!(pile.validation_state(&intact), Some(ValidationState::Validated));
+        assert_eq!(pile.validation_state(&corrupted), Some(ValidationState::Invalid));
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.validate_all().unwrap(), vec![corrupted]);
+    }
+
+    #[test]
+    fn load_verified_rejects_corrupted_piles() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let hash = pile.insert_slice(&[1u8; 100]).unwrap();
+        drop(pile);
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load_verified(&tmp_pile).unwrap();
+        assert_eq!(pile.validation_state(&hash), Some(ValidationState::Validated));
+        drop(pile);
+
+        let (_tmp_dir, tmp_pile, _, corrupted) = corrupted_pile();
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load_verified(&tmp_pile),
+            Err(LoadError::ValidationFailed(invalid)) if invalid == vec![corrupted]
+        ));
+    }
+
+    #[test]
+    fn with_bytes_validates_like_get_blob() {
+        let (_tmp_dir, tmp_pile, intact, corrupted) = corrupted_pile();
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.with_bytes(&intact, |bytes| bytes.to_vec()).unwrap(), Some(vec![1u8; 100]));
+        assert_eq!(pile.validation_state(&intact), Some(ValidationState::Validated));
+        assert_eq!(pile.with_bytes(&[0; 32], |bytes| bytes[0]).unwrap(), None);
+        assert!(matches!(
+            pile.with_bytes(&corrupted, |_| ()),
+            Err(GetError::ValidationError { .. })
+        ));
+    }
+
+    #[test]
+    fn validation_state_doesnt_validate() {
+        let (_tmp_dir, tmp_pile, intact, corrupted) = corrupted_pile();
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        for _ in 0..2 {
+            assert_eq!(pile.validation_state(&intact), Some(ValidationState::Unvalidated));
+            assert_eq!(pile.validation_state(&corrupted), Some(ValidationState::Unvalidated));
+        }
+        assert_eq!(pile.validation_state(&[0; 32]), None);
+
+        pile.get_blob(&intact).unwrap();
+        pile.get_blob(&corrupted).unwrap_err();
+        assert_eq!(pile.validation_state(&intact), Some(ValidationState::Validated));
+        assert_eq!(pile.validation_state(&corrupted), Some(ValidationState::Invalid));
+    }
+
+    #[test]
+    fn get_unchecked_skips_validation() {
+        let (_tmp_dir, tmp_pile, _, corrupted) = corrupted_pile();
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+
+        let mut expected = [2u8; 100];
+        expected[0] ^= 1;
+        assert_eq!(&*pile.get_unchecked(&corrupted).unwrap().unwrap(), &expected[..]);
+        assert_eq!(pile.validation_state(&corrupted), Some(ValidationState::Unvalidated));
+        assert_eq!(pile.get_unchecked(&[0; 32]).unwrap(), None);
+
+        // Blobs known to be invalid are returned as well.
+        pile.get_blob(&corrupted).unwrap_err();
+        assert_eq!(&*pile.get_unchecked(&corrupted).unwrap().unwrap(), &expected[..]);
+    }
+
+    #[test]
+    fn stats_account_for_padding() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![2u8; 64])).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![2u8; 64])).unwrap();
+
+        let stats = pile.stats();
+        assert_eq!(stats.file_length, 64 + (64 + 128) + (64 + 64));
+        assert_eq!(stats.blob_count, 2);
+        assert_eq!(stats.total_payload_bytes, 164);
+        assert_eq!(stats.total_padding_bytes, 28);
+        assert_eq!(stats.header_overhead_bytes, 128);
+    }
+
+    #[test]
+    fn size_histogram_buckets_by_powers_of_two() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        assert!(pile.size_histogram().is_empty());
+        for length in [0, 1, 63, 64, 100, 4096] {
+            pile.insert_slice(&vec![length as u8; length]).unwrap();
+        }
+        let mut expected = vec![0; 14];
+        expected[0] = 1;
+        expected[1] = 1;
+        expected[6] = 1;
+        expected[7] = 2;
+        expected[13] = 1;
+        assert_eq!(pile.size_histogram(), expected);
+    }
+
+    #[test]
+    fn stats_count_inserts() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.insert_many(&[
+            Bytes::from_source(vec![2u8; 64]),
+            Bytes::from_source(vec![1u8; 100]),
+        ])
+        .unwrap();
+        pile.insert_reader(&[3u8; 10][..]).unwrap();
+        pile.insert_reader(&[2u8; 64][..]).unwrap();
+
+        let stats = pile.stats();
+        assert_eq!(stats.inserts_total, 6);
+        assert_eq!(stats.inserts_deduplicated, 3);
+        assert_eq!(stats.bytes_written, 100 + 64 + 10);
+        assert_eq!(stats.bytes_deduplicated, 100 + 100 + 64);
+    }
+
+    #[test]
+    fn get_many_keeps_order() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let first = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let second = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+
+        let results = pile.get_many(&[second, [0; 32], first]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(&*results[0].as_ref().unwrap().clone().unwrap(), &[2u8; 100][..]);
+        assert!(results[1].as_ref().unwrap().is_none());
+        assert_eq!(&*results[2].as_ref().unwrap().clone().unwrap(), &[1u8; 100][..]);
+    }
+
+    #[test]
+    fn access_pattern_hints() {
+        let (_tmp_dir, _, mut pile) = temp_pile();
+        let hash = pile.insert_slice(&[1u8; 100]).unwrap();
+        for pattern in [AccessPattern::Sequential, AccessPattern::Normal, AccessPattern::Random] {
+            pile.advise(pattern).unwrap();
+            assert_eq!(*pile.access_pattern.lock().unwrap(), pattern);
+            assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &[1u8; 100][..]);
+        }
+
+        // The hint carries over to the mapping of the compacted file.
+        pile.compact_in_place().unwrap();
+        assert_eq!(*pile.access_pattern.lock().unwrap(), AccessPattern::Random);
+        assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &[1u8; 100][..]);
+    }
+
+    #[test]
+    fn get_slice_returns_a_window() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let hash = pile.insert_blob(&Bytes::from_source((0..100u8).collect::<Vec<_>>())).unwrap();
+
+        let blob = pile.get_blob(&hash).unwrap().unwrap();
+        let window = pile.get_slice(&hash, 10..20).unwrap().unwrap();
+        assert_eq!(&*window, &(10..20u8).collect::<Vec<_>>()[..]);
+        // The window points into the stored blob instead of copying it.
+        assert_eq!(window.as_ptr(), blob[10..].as_ptr());
+        assert_eq!(pile.get_slice(&hash, 100..100).unwrap().unwrap().len(), 0);
+        assert!(matches!(
+            pile.get_slice(&hash, 90..110),
+            Err(GetError::OutOfRange { length: 100, .. })
+        ));
+        assert_eq!(pile.get_slice(&[0; 32], 0..10).unwrap(), None);
+    }
+
+    #[test]
+    fn prefetch_hints_known_blobs() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let first = pile.insert_slice(&[1u8; 100]).unwrap();
+        let second = pile.insert_slice(&[2u8; 5000]).unwrap();
+        let empty = pile.insert_slice(&[]).unwrap();
+
+        assert_eq!(pile.prefetch(&[second, [0; 32], first, empty]), 2);
+        assert_eq!(&*pile.get_blob(&second).unwrap().unwrap(), &[2u8; 5000][..]);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load_no_mmap(&tmp_pile).unwrap();
+        assert_eq!(pile.prefetch(&[first, second]), 0);
+    }
+
+    #[test]
+    fn bloom_filter_grows_with_the_pile() {
+        const MAX_PILE_SIZE: usize = 1 << 22;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let missing: Hash = *blake3::hash(b"missing").as_bytes();
+        let initial_capacity = pile.bloom.read().unwrap().capacity;
+
+        let hashes: Vec<_> = (0..initial_capacity as u32 + 1)
+            .map(|i| pile.insert_slice(&i.to_le_bytes()).unwrap())
+            .collect();
+        assert!(pile.bloom.read().unwrap().capacity > initial_capacity);
+        assert!(hashes.iter().all(|hash| pile.probably_contains(hash)));
+        // The miss is answered by the filter alone.
+        assert!(!pile.bloom.read().unwrap().contains(&missing));
+        assert!(!pile.contains(&missing));
+    }
+
+    #[test]
+    fn from_open_file() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let first = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        drop(pile);
+
+        let file = OpenOptions::new().read(true).write(true).open(&tmp_pile).unwrap();
+        let pile: Pile<MAX_PILE_SIZE> = Pile::from_file(file, MAX_PILE_SIZE).unwrap();
+        assert!(pile.contains(&first));
+        let second = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.contains(&first));
+        assert!(pile.contains(&second));
+    }
+
+    #[test]
+    fn iter_in_write_order() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let first = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let second = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        pile.remove(&first).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+
+        let hashes: Vec<Hash> = pile.iter_in_order().map(|record| record.unwrap().0).collect();
+        assert_eq!(hashes, vec![first, second, first]);
+    }
+
+    #[test]
+    fn get_with_timestamp_returns_the_insert_time() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let hash = pile.insert_with_timestamp(&Bytes::from_source(vec![2u8; 100]), 1234).unwrap();
+        let other = pile.insert_slice(&[3u8; 100]).unwrap();
+
+        let (bytes, timestamp) = pile.get_with_timestamp(&hash).unwrap().unwrap();
+        assert_eq!(bytes[..], [2u8; 100]);
+        assert_eq!(timestamp, 1234);
+        drop(bytes);
+        assert!(pile.get_with_timestamp(&other).unwrap().unwrap().1 > 1234);
+        assert_eq!(pile.get_with_timestamp(&[0; 32]).unwrap(), None);
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.get_with_timestamp(&hash).unwrap().unwrap().1, 1234);
+    }
+
+    #[test]
+    fn validation_cache_forgets_old_blobs() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let first = pile.insert_slice(&[1u8; 100]).unwrap();
+        let second = pile.insert_slice(&[2u8; 100]).unwrap();
+        drop(pile);
+
+        let validated = Arc::new(Mutex::new(Vec::new()));
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load_with_validation_cache(&tmp_pile, 1)
+            .unwrap()
+            .with_validation_hook({
+                let validated = validated.clone();
+                move |hash, _| validated.lock().unwrap().push(hash)
+            });
+        for hash in [first, first, second, second, first] {
+            assert!(pile.get_blob(&hash).unwrap().is_some());
+        }
+        assert_eq!(*validated.lock().unwrap(), vec![first, second, first]);
+        assert_eq!(pile.validation_state(&first), Some(ValidationState::Validated));
+        assert_eq!(pile.validation_state(&second), Some(ValidationState::Unvalidated));
+
+        // Inserted blobs are trusted until other blobs push them out.
+        let third = pile.insert_slice(&[3u8; 100]).unwrap();
+        assert_eq!(pile.validation_state(&third), Some(ValidationState::Validated));
+        assert!(pile.get_blob(&third).unwrap().is_some());
+        assert_eq!(validated.lock().unwrap().len(), 3);
+        assert_eq!(pile.validation_state(&first), Some(ValidationState::Unvalidated));
+    }
+
+    #[test]
+    fn index_sidecar_skips_the_scan() {
+        let (tmp_dir, tmp_pile, pile) = temp_pile();
+        let first = pile.insert_slice(&[1u8; 100]).unwrap();
+        let removed = pile.insert_slice(&[2u8; 100]).unwrap();
+        let last = pile.insert_slice(&[3u8; 100]).unwrap();
+        pile.remove(&removed).unwrap();
+        pile.commit_branch([1; 16], first).unwrap();
+        pile.set_head("main", last).unwrap();
+        // The sidecar describes the file, not the index.
+        pile.retain(|hash| *hash != first);
+        pile.flush_with_index().unwrap();
+        drop(pile);
+        assert!(tmp_dir.path().join("test.pile.idx").exists());
+
+        // A scan would fail at the first record, so this pile comes from the sidecar.
+        let mut file_bytes = std::fs::read(&tmp_pile).unwrap();
+        file_bytes[64] ^= 1;
+        std::fs::write(&tmp_pile, &file_bytes).unwrap();
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.len(), 2);
+        assert!(pile.contains(&first));
+        assert!(!pile.contains(&removed));
+        // The footer the sidecar was written with still covers the flipped byte.
+        assert!(!pile.verify_footer().unwrap());
+        assert_eq!(pile.get_blob(&last).unwrap().unwrap()[..], [3u8; 100]);
+        assert_eq!(pile.get_branch([1; 16]), Some(first));
+        assert_eq!(pile.get_head("main"), Some(last));
+        pile.insert_slice(&[4u8; 100]).unwrap();
+        drop(pile);
+
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load(&tmp_pile),
+            Err(LoadError::MagicMarkerError)
+        ));
+    }
+
+    #[test]
+    fn truncate_to_rolls_back() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let mut pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let first = pile.insert_slice(&[1u8; 100]).unwrap();
+        let second = pile.insert_slice(&[2u8; 100]).unwrap();
+        pile.remove(&first).unwrap();
+        pile.insert_slice(&[1u8; 100]).unwrap();
+        let third = pile.insert_slice(&[3u8; 100]).unwrap();
+        pile.set_head("main", third).unwrap();
+        let old_bytes = pile.get_blob(&third).unwrap().unwrap();
+
+        assert!(matches!(pile.truncate_to(&[0; 32]), Err(LoadError::NotFound)));
+
+        pile.truncate_to_last(&first).unwrap();
+        assert!(pile.contains(&first));
+        assert!(pile.contains(&second));
+        assert!(!pile.contains(&third));
+        assert_eq!(pile.get_head("main"), None);
+        assert_eq!(old_bytes[..], [3u8; 100]);
+
+        pile.truncate_to(&first).unwrap();
+        assert_eq!(pile.len(), 1);
+        assert_eq!(pile.used(), 64 + (64 + 128));
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.contains(&first));
+        assert!(!pile.contains(&second));
+    }
+
+    #[test]
+    fn writers_lock_the_file() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load(&tmp_pile),
+            Err(LoadError::AlreadyLocked)
+        ));
+        // Readers don't take the lock, so they can open the file next to the writer.
+        let reader: Pile<MAX_PILE_SIZE> = Pile::open_read_only(&tmp_pile).unwrap();
+        let other_reader: Pile<MAX_PILE_SIZE> = Pile::open_read_only(&tmp_pile).unwrap();
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        drop((reader, other_reader, pile));
+        Pile::<MAX_PILE_SIZE>::load(&tmp_pile).unwrap();
+    }
+
+    #[test]
+    fn given_timestamps_make_files_reproducible() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let blob = Bytes::from_source(vec![1u8; 100]);
+        let other = Bytes::from_source(vec![2u8; 100]);
+        let other_hash: Hash = Blake3::digest(&other).into();
+        let mut files = Vec::new();
+        for name in ["a.pile", "b.pile"] {
+            let tmp_pile = tmp_dir.path().join(name);
+            let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+            let hash = pile.insert_with_timestamp(&blob, 1000).unwrap();
+            pile.insert_with_timestamp(&blob, 2000).unwrap();
+            pile.insert_unvalidated_with_timestamp(other_hash, &other, 3000)
+                .unwrap();
+            assert_eq!(pile.timestamp(&hash), Some(1000));
+            assert_eq!(pile.timestamp(&other_hash), Some(3000));
+            drop(pile);
+            files.push(std::fs::read(&tmp_pile).unwrap());
+        }
+        assert_eq!(files[0], files[1]);
+    }
+
+    #[cfg(feature = "io_uring")]
+    #[test]
+    fn io_uring_writes_the_same_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let blobs: Vec<Bytes> = [0, 1, 100, 4000]
+            .into_iter()
+            .map(|len| Bytes::from_source(vec![len as u8; len]))
+            .collect();
+        let mut files = Vec::new();
+        for io_uring in [false, true] {
+            let tmp_pile = tmp_dir.path().join(format!("{io_uring}.pile"));
+            let pile: Pile<MAX_PILE_SIZE> = if io_uring {
+                Pile::load_with_io_uring(&tmp_pile).unwrap()
+            } else {
+                Pile::load(&tmp_pile).unwrap()
+            };
+            for blob in &blobs {
+                let hash = pile.insert_with_timestamp(blob, 1000).unwrap();
+                assert_eq!(pile.get_blob(&hash).unwrap().unwrap(), *blob);
+            }
+            drop(pile);
+            files.push(std::fs::read(&tmp_pile).unwrap());
+        }
+        assert_eq!(files[0], files[1]);
+    }
+
+    #[test]
+    fn get_header_reads_the_stored_header() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let hash = pile
+            .insert_with_timestamp(&Bytes::from_source(vec![1u8; 100]), 1234)
+            .unwrap();
+        assert_eq!(
+            pile.get_header(&hash),
+            Some(RawHeader {
+                magic_marker: MAGIC_MARKER_BLOB,
+                timestamp: 1234,
+                length: 100,
+                hash,
+            })
+        );
+        assert_eq!(pile.get_header(&[0; 32]), None);
+    }
+
+    #[test]
+    fn load_sampled_checks_some_blobs() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let corrupted = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        for i in 0..100u8 {
+            pile.insert_blob(&Bytes::from_source(vec![i; 64])).unwrap();
+        }
+        drop(pile);
+
+        let mut file = OpenOptions::new().write(true).open(&tmp_pile).unwrap();
+        file.seek(SeekFrom::Start(64 + 64)).unwrap();
+        file.write_all(&[2]).unwrap();
+        drop(file);
+
+        Pile::<MAX_PILE_SIZE>::load_sampled(&tmp_pile, 0.0, 0).unwrap();
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load_sampled(&tmp_pile, 1.0, 0),
+            Err(LoadError::ValidationFailed(invalid)) if invalid == vec![corrupted]
+        ));
+        let checked = Pile::<MAX_PILE_SIZE>::load_sampled(&tmp_pile, 0.1, 0).is_err();
+        let checked_again = Pile::<MAX_PILE_SIZE>::load_sampled(&tmp_pile, 0.1, 0).is_err();
+        assert_eq!(checked, checked_again);
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load_sampled(&tmp_pile, f64::NAN, 0),
+            Err(LoadError::InvalidSampleRate(rate)) if rate.is_nan()
+        ));
+    }
+
+    #[test]
+    fn build_pile_from_iterator() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let mut builder: PileBuilder = (0..3u8)
+            .map(|i| Bytes::from_source(vec![i; 100]))
+            .collect();
+        builder.push(Bytes::from_source(vec![0u8; 100]));
+        let pile: Pile<MAX_PILE_SIZE> = builder.build(&tmp_pile).unwrap();
+        assert_eq!(pile.len(), 3);
+        assert_eq!(pile.used(), 64 + 3 * (64 + 128));
+    }
+
+    #[test]
+    fn capacity_matches_inserts() {
+        const MAX_PILE_SIZE: usize = 1024;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.remaining_capacity(), 1024 - 64);
+
+        assert!(pile.would_fit(1024 - 128));
+        assert!(!pile.would_fit(1024 - 127));
+        assert!(matches!(
+            pile.insert_blob(&Bytes::from_source(vec![1u8; 1024 - 127])),
+            Err(InsertError::BlobTooLarge {
+                size: 897,
+                max: 896
+            })
+        ));
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 1024 - 128])).unwrap();
+        assert!(matches!(
+            pile.insert_blob(&Bytes::from_source(vec![2u8; 1])),
+            Err(InsertError::PileTooLarge)
+        ));
+        assert_eq!(pile.remaining_capacity(), 0);
+        assert!(!pile.would_fit(0));
+    }
+
+    #[test]
+    fn would_fit_stops_at_the_largest_blob() {
+        const MAX_PILE_SIZE: usize = 1024;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        std::fs::write(&tmp_pile, []).unwrap();
+        // Without a version record the whole file is free,
+        // but blobs are still limited to what a new pile could hold.
+        let pile: Pile<MAX_PILE_SIZE> = Pile::open_read_only(&tmp_pile).unwrap();
+        assert_eq!(pile.remaining_capacity(), 1024);
+        assert!(pile.would_fit(1024 - 128));
+        assert!(!pile.would_fit(1024 - 127));
+        assert!(!pile.would_fit(1024 - 64));
+    }
+
+    #[test]
+    fn estimate_append_size_matches_the_growth() {
+        let (_tmp_dir, _, pile) = temp_pile();
+        let batch = [
+            Bytes::from_source(vec![1u8; 100]),
+            Bytes::from_source(vec![2u8; 64]),
+            Bytes::from_source(Vec::new()),
+        ];
+        let estimate = Pile::<MAX_PILE_SIZE>::estimate_append_size(&batch);
+        assert_eq!(estimate, (64 + 128) + (64 + 64) + 64);
+        assert_eq!(Pile::<MAX_PILE_SIZE>::estimate_append_size(&[]), 0);
+
+        let used = pile.used();
+        for blob in &batch {
+            pile.insert_blob(blob).unwrap();
+        }
+        assert_eq!(pile.used() - used, estimate);
+    }
+
+    #[test]
+    fn max_size_and_used_track_the_file() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        assert_eq!(pile.max_size(), MAX_PILE_SIZE);
+        assert_eq!(pile.used(), 64);
+        pile.insert_slice(&[1u8; 100]).unwrap();
+        pile.commit_branch([1; 16], [0; 32]).unwrap();
+        assert_eq!(pile.used(), 64 + (64 + 128) + 64);
+        assert_eq!(pile.used() + pile.remaining_capacity(), pile.max_size());
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> =
+            Pile::load_with_capacity(&tmp_pile, MAX_PILE_SIZE / 2).unwrap();
+        assert_eq!(pile.max_size(), MAX_PILE_SIZE / 2);
+        assert_eq!(pile.used(), std::fs::metadata(&tmp_pile).unwrap().len() as usize);
+    }
+
+    #[test]
+    fn sync_appended_only_syncs_the_tail() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.flush().unwrap();
+        let synced_length =
+            |pile: &Pile<MAX_PILE_SIZE>| pile.file.lock().unwrap().last_synced_length;
+        assert_eq!(synced_length(&pile), pile.used());
+
+        pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        assert!(synced_length(&pile) < pile.used());
+        pile.sync_appended().unwrap();
+        assert_eq!(synced_length(&pile), pile.used());
+        pile.sync_appended().unwrap();
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::open_read_only(&tmp_pile).unwrap();
+        assert_eq!(pile.len(), 2);
+        assert!(matches!(pile.sync_appended(), Err(FlushError::ReadOnly)));
+    }
+
+    #[test]
+    fn recover_after_panicking_writer() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let kept = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let mut append = pile.file.lock().unwrap();
+            append.file.write_all(&[0xAA; 80]).unwrap();
+            append.length += 128;
+            panic!("writer died");
+        });
+        assert!(result.is_err());
+        assert!(matches!(
+            pile.insert_blob(&Bytes::from_source(vec![2u8; 100])),
+            Err(InsertError::PoisonError)
+        ));
+
+        pile.recover_from_poison().unwrap();
+        let added = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.contains(&kept));
+        assert!(pile.contains(&added));
+    }
+
+    #[test]
+    fn footer_detects_corruption() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        assert!(!pile.verify_footer().unwrap());
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.flush_with_footer().unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        pile.flush_with_footer().unwrap();
+        assert!(pile.verify_footer().unwrap());
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.verify_footer().unwrap());
+        assert_eq!(pile.len(), 2);
+        drop(pile);
+
+        let mut file_bytes = std::fs::read(&tmp_pile).unwrap();
+        file_bytes[64 + 64] ^= 1;
+        std::fs::write(&tmp_pile, &file_bytes).unwrap();
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(!pile.verify_footer().unwrap());
+    }
+
+    #[test]
+    fn latest_head_wins() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let first = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let second = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        pile.set_head("main", first).unwrap();
+        pile.set_head("main", second).unwrap();
+        pile.set_head("old", first).unwrap();
+        assert!(matches!(
+            pile.set_head("a name that is too long", first),
+            Err(InsertError::InvalidHeadName)
+        ));
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.get_head("main"), Some(second));
+        assert_eq!(pile.get_head("old"), Some(first));
+        assert_eq!(pile.get_head("missing"), None);
+        assert_eq!(pile.iter_in_order().count(), 2);
+    }
+
+    #[test]
+    fn removed_blobs_stay_removed() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+
+        let kept = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let removed = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        assert!(pile.remove(&removed).unwrap());
+        assert!(!pile.remove(&removed).unwrap());
+        assert!(pile.get_blob(&removed).unwrap().is_none());
+        pile.flush().unwrap();
+        drop(pile);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert!(pile.contains(&kept));
+        assert!(!pile.contains(&removed));
+    }
+
+    #[test]
+    fn growable_mapping() {
+        const MAX_PILE_SIZE: usize = 1 << 30;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load_growable(&tmp_pile).unwrap();
+        pile.advise(AccessPattern::Random).unwrap();
+
+        let first = Bytes::from_source(vec![1u8; 600_000]);
+        let first_hash = pile.insert_blob(&first).unwrap();
+        let first_stored = pile.get_blob(&first_hash).unwrap().unwrap();
+
+        let hashes: Vec<Hash> = (2..6u8)
+            .map(|i| pile.insert_blob(&Bytes::from_source(vec![i; 600_000])).unwrap())
+            .collect();
+
+        assert_eq!(&first_stored[..], &first[..]);
+        for (i, hash) in (2..6u8).zip(&hashes) {
+            assert_eq!(&*pile.get_blob(hash).unwrap().unwrap(), &vec![i; 600_000][..]);
+        }
+    }
+
+    #[test]
+    fn compacted_growable_piles_stay_growable() {
+        const MAX_PILE_SIZE: usize = 1 << 30;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let mut pile: Pile<MAX_PILE_SIZE> = Pile::load_growable(&tmp_pile).unwrap();
+        let removed = pile.insert_slice(&[1u8; 100]).unwrap();
+        pile.remove(&removed).unwrap();
+        pile.compact_in_place().unwrap();
+
+        let mapping_len = |pile: &Pile<MAX_PILE_SIZE>| match &pile.storage {
+            Storage::Mapped(mmap) => mmap.read().unwrap().len(),
+            Storage::Read(_) => unreachable!(),
+        };
+        assert!(mapping_len(&pile) < MAX_PILE_SIZE);
+        let hash = pile.insert_slice(&vec![2u8; 2_000_000]).unwrap();
+        assert!(mapping_len(&pile) < MAX_PILE_SIZE);
+        assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &vec![2u8; 2_000_000][..]);
+    }
+
+    #[test]
+    fn extend_from_other_pile() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let src: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_dir.path().join("src.pile")).unwrap();
+        let mut dst: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_dir.path().join("dst.pile")).unwrap();
+
+        let hashes: Vec<Hash> = (0..10u8)
+            .map(|i| src.insert_blob(&Bytes::from_source(vec![i; 100])).unwrap())
+            .collect();
+        dst.extend(&src);
+
+        for hash in &hashes {
+            assert_eq!(dst.get_blob(hash).unwrap(), src.get_blob(hash).unwrap());
+        }
+        assert_eq!(dst.len(), hashes.len());
+    }
+
+    #[test]
+    fn merge_adds_missing_blobs() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let a: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_dir.path().join("a.pile")).unwrap();
+        let b: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_dir.path().join("b.pile")).unwrap();
+
+        let shared = Bytes::from_source(vec![1u8; 100]);
+        a.insert_blob(&shared).unwrap();
+        b.insert_blob(&shared).unwrap();
+        let only_b = b.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+
+        let report = a.merge_from(&b).unwrap();
+        assert_eq!(report, MergeReport { added: 1, skipped: 1 });
+        assert_eq!(a.get_blob(&only_b).unwrap(), b.get_blob(&only_b).unwrap());
+        assert_eq!(a.len(), 2);
+    }
+
+    #[test]
+    fn copy_to_keeps_timestamps_and_skips_invalid_blobs() {
+        let (_tmp_dir, tmp_pile, intact, corrupted) = corrupted_pile();
+        let source: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let shared = source.insert_with_timestamp(&Bytes::from_source(vec![3u8; 100]), 5).unwrap();
+        assert_eq!(source.validate_all().unwrap(), vec![corrupted]);
+
+        let dest_dir = tempfile::tempdir().unwrap();
+        let dest: Pile<{ 1 << 16 }> = Pile::load(&dest_dir.path().join("dest.pile")).unwrap();
+        dest.insert_slice(&[3u8; 100]).unwrap();
+        assert_eq!(source.copy_to(&dest).unwrap(), 1);
+        assert_eq!(source.copy_to(&dest).unwrap(), 0);
+
+        assert!(!dest.contains(&corrupted));
+        assert_eq!(dest.timestamp(&intact), source.timestamp(&intact));
+        assert_ne!(dest.timestamp(&shared), Some(5));
+        assert_eq!(dest.validation_state(&intact), Some(ValidationState::Validated));
+        assert_eq!(&*dest.get_blob(&intact).unwrap().unwrap(), &[1u8; 100][..]);
+    }
+
+    #[test]
+    fn diff_lists_missing_blobs() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let a: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_dir.path().join("a.pile")).unwrap();
+        let b: Pile<1024> = Pile::load(&tmp_dir.path().join("b.pile")).unwrap();
+        assert!(a.diff(&b).is_empty());
+
+        a.insert_slice(&[1u8; 100]).unwrap();
+        b.insert_slice(&[1u8; 100]).unwrap();
+        let only_a = a.insert_slice(&[2u8; 100]).unwrap();
+        let only_b = b.insert_slice(&[3u8; 100]).unwrap();
+        assert_eq!(a.diff(&b), vec![only_a]);
+        assert_eq!(b.diff(&a), vec![only_b]);
+
+        a.remove(&only_a).unwrap();
+        assert!(a.diff(&b).is_empty());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_and_plain_blobs() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+
+        let compressible = Bytes::from_source(b"{\"key\": \"value\"}".repeat(100));
+        let plain = Bytes::from_source(vec![7u8; 100]);
+        let compressed_hash = pile.insert_blob_compressed(&compressible).unwrap();
+        let plain_hash = pile.insert_blob(&plain).unwrap();
+        pile.flush().unwrap();
+        drop(pile);
+
+        assert!(std::fs::metadata(&tmp_pile).unwrap().len() < compressible.len() as u64);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.get_blob(&compressed_hash).unwrap().unwrap(), compressible);
+        assert_eq!(pile.get_blob(&plain_hash).unwrap().unwrap(), plain);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compaction_keeps_blobs_compressed() {
+        let (tmp_dir, tmp_pile, pile) = temp_pile();
+        let compressible = Bytes::from_source(b"{\"key\": \"value\"}".repeat(100));
+        let hash = pile.insert_blob_compressed(&compressible).unwrap();
+        let corrupted = pile.insert_blob_compressed(&Bytes::from_source(vec![1u8; 1000])).unwrap();
+
+        let compacted = pile.compact(&tmp_dir.path().join("compacted.pile")).unwrap();
+        assert_eq!(compacted.used(), pile.used());
+        assert_eq!(compacted.get_blob(&hash).unwrap().unwrap(), compressible);
+        let offset = pile.offset(&corrupted).unwrap();
+        drop(compacted);
+        drop(pile);
+
+        let mut file = OpenOptions::new().write(true).open(&tmp_pile).unwrap();
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        file.write_all(&[0xFF; 8]).unwrap();
+        drop(file);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let records: Vec<_> = pile.iter_in_order().collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].as_ref().unwrap(), &(hash, compressible));
+        assert!(matches!(records[1], Err(LoadError::IoError(_))));
+    }
+
+    #[cfg(feature = "encrypt")]
+    #[test]
+    fn encrypted_round_trip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let key = [7u8; 32];
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load_encrypted(&tmp_pile, key).unwrap();
+        assert!(pile.is_encrypted());
+        assert_eq!(pile.alignment(), ENCRYPTED_ALIGNMENT);
+
+        let secret = Bytes::from_source(b"top secret".repeat(10));
+        let hash = pile.insert_blob(&secret).unwrap();
+        assert_eq!(hash, <[u8; 32]>::from(Blake3::digest(&secret)));
+        let file_len = std::fs::metadata(&tmp_pile).unwrap().len();
+        assert_eq!(pile.insert_blob(&secret).unwrap(), hash);
+        assert_eq!(std::fs::metadata(&tmp_pile).unwrap().len(), file_len);
+        assert_eq!(pile.get_blob(&hash).unwrap().unwrap(), secret);
+        pile.flush().unwrap();
+        drop(pile);
+
+        let file = std::fs::read(&tmp_pile).unwrap();
+        assert!(!file.windows(10).any(|window| window == b"top secret"));
+
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load(&tmp_pile),
+            Err(LoadError::KeyMismatch)
+        ));
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load_encrypted(&tmp_pile, [8u8; 32]),
+            Err(LoadError::KeyMismatch)
+        ));
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load_encrypted(&tmp_pile, key).unwrap();
+        assert_eq!(pile.get_fast_checked(&hash).unwrap().unwrap(), secret);
+        assert_eq!(pile.iter_in_order().next().unwrap().unwrap(), (hash, secret.clone()));
+
+        // Reads leave the ciphertext in the index, only the cache holds plaintext.
+        *pile.plaintext_cache.lock().unwrap() = PlaintextCache::new(150);
+        let other = pile.insert_slice(&[1u8; 100]).unwrap();
+        assert_eq!(pile.get_blob(&hash).unwrap().unwrap(), secret);
+        assert_eq!(&pile.get_blob(&other).unwrap().unwrap()[..], &[1u8; 100][..]);
+        assert!(pile.plaintext_cache.lock().unwrap().size <= 150);
+        let index = pile.index.shard(&hash).read().unwrap();
+        assert!(index[&hash].lock().unwrap().encrypted);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_load_matches_serial_load() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let hashes: Vec<Hash> = (0..100u8)
+            .map(|i| pile.insert_blob(&Bytes::from_source(vec![i; 100])).unwrap())
+            .collect();
+        pile.remove(&hashes[10]).unwrap();
+        pile.remove(&hashes[20]).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![20u8; 100])).unwrap();
+        pile.flush().unwrap();
+        drop(pile);
+
+        let serial: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let mut serial_hashes: Vec<Hash> = serial.hashes().collect();
+        drop(serial);
+        let parallel: Pile<MAX_PILE_SIZE> = Pile::load_parallel(&tmp_pile).unwrap();
+        let mut parallel_hashes: Vec<Hash> = parallel.hashes().collect();
+        serial_hashes.sort();
+        parallel_hashes.sort();
+        assert_eq!(serial_hashes, parallel_hashes);
+        assert!(!parallel.contains(&hashes[10]));
+        assert!(parallel.contains(&hashes[20]));
+    }
+
+    #[test]
+    fn compact_keeps_live_blobs() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let compacted_pile = tmp_dir.path().join("compacted.pile");
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+
+        let kept = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let removed = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        pile.remove(&removed).unwrap();
+
+        let compacted = pile.compact(&compacted_pile).unwrap();
+        compacted.flush().unwrap();
+        drop(compacted);
+
+        let compacted: Pile<MAX_PILE_SIZE> = Pile::load(&compacted_pile).unwrap();
+        assert_eq!(compacted.hashes().collect::<Vec<_>>(), vec![kept]);
+        assert_eq!(std::fs::metadata(&compacted_pile).unwrap().len(), 64 + (64 + 128));
+    }
+
+    #[test]
+    fn rebuild_index_picks_up_appends() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let tmp_pile = tmp_dir.path().join("test.pile");
+        let other_pile = tmp_dir.path().join("other.pile");
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        drop(pile);
+
+        // The records of another pile stand in for the appends of a writer.
+        let other: Pile<MAX_PILE_SIZE> = Pile::load(&other_pile).unwrap();
+        let second = other.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        let third = other.insert_blob(&Bytes::from_source(vec![3u8; 100])).unwrap();
+        drop(other);
+        let appended = std::fs::read(&other_pile).unwrap().split_off(64);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::open_read_only(&tmp_pile).unwrap();
+        assert_eq!(pile.rebuild_index().unwrap(), 0);
+
+        // The second record is only partially written.
+        let mut file = OpenOptions::new().append(true).open(&tmp_pile).unwrap();
+        file.write_all(&appended[..(64 + 128) + 64]).unwrap();
+        assert_eq!(pile.rebuild_index().unwrap(), 1);
+        assert!(pile.contains(&second));
+        assert!(!pile.contains(&third));
+
+        file.write_all(&appended[(64 + 128) + 64..]).unwrap();
+        assert_eq!(pile.rebuild_index().unwrap(), 1);
+        assert_eq!(&*pile.get_blob(&third).unwrap().unwrap(), &[3u8; 100][..]);
+        assert_eq!(pile.len(), 3);
+
+        // Appending the same records again keeps the validated entries.
+        file.write_all(&appended).unwrap();
+        assert_eq!(pile.rebuild_index().unwrap(), 0);
+        assert_eq!(pile.validation_state(&third), Some(ValidationState::Validated));
+        drop(file);
+
+        // A writing pile can be followed while it is open.
+        let writer: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        let fourth = writer.insert_slice(&[4u8; 100]).unwrap();
+        assert_eq!(pile.rebuild_index().unwrap(), 1);
+        assert!(pile.contains(&fourth));
+    }
+
+    #[test]
+    fn get_slice_validates_the_whole_blob() {
+        let (_tmp_dir, tmp_pile, pile) = temp_pile();
+        let intact = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        let corrupted = pile.insert_blob(&Bytes::from_source(vec![2u8; 100])).unwrap();
+        drop(pile);
+
+        // Corrupt the end of the second blob, outside of the windows read below.
+        let mut file = OpenOptions::new().write(true).open(&tmp_pile).unwrap();
+        file.seek(SeekFrom::Start(64 + (64 + 128) + 64 + 90)).unwrap();
+        file.write_all(&[3]).unwrap();
+        drop(file);
+
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+        assert_eq!(pile.validation_state(&intact), Some(ValidationState::Unvalidated));
+        assert_eq!(&*pile.get_slice(&intact, 0..10).unwrap().unwrap(), &[1u8; 10][..]);
+        assert_eq!(pile.validation_state(&intact), Some(ValidationState::Validated));
+        assert_eq!(&*pile.get_slice(&intact, 10..20).unwrap().unwrap(), &[1u8; 10][..]);
+        assert!(matches!(
+            pile.get_slice(&corrupted, 0..10),
+            Err(GetError::ValidationError { .. })
+        ));
+        assert_eq!(pile.validation_state(&corrupted), Some(ValidationState::Invalid));
+    }
+
+    #[test]
+    fn get_verified_slice_checks_the_blob_once() {
+        let (_tmp_dir, tmp_pile, intact, corrupted) = corrupted_pile();
+        let pile: Pile<MAX_PILE_SIZE> = Pile::load(&tmp_pile).unwrap();
+
+        let blob = pile.get_verified_slice(&intact, 0..100).unwrap().unwrap();
+        assert_eq!(pile.validation_state(&intact), Some(ValidationState::Validated));
+        let window = pile.get_verified_slice(&intact, 40..60).unwrap().unwrap();
+        assert_eq!(&*window, &[1u8; 20][..]);
+        assert_eq!(window.as_ptr(), blob[40..].as_ptr());
+        assert!(matches!(
+            pile.get_verified_slice(&intact, 60..101),
+            Err(GetError::OutOfRange { length: 100, .. })
+        ));
+
+        // The corrupted byte is the first one, outside of the window.
+        assert!(matches!(
+            pile.get_verified_slice(&corrupted, 50..60),
+            Err(GetError::ValidationError { .. })
+        ));
+        assert_eq!(pile.validation_state(&corrupted), Some(ValidationState::Invalid));
+        assert!(pile.get_blob(&corrupted).is_err());
+        assert_eq!(pile.get_verified_slice(&[0; 32], 0..10).unwrap(), None);
+    }
+
+    #[test]
+    fn pile_set_rolls_over_to_new_files() {
+        const MAX_PILE_SIZE: usize = 1 << 12;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let set: PileSet<MAX_PILE_SIZE> = PileSet::open(tmp_dir.path()).unwrap();
+        // Three records of 64 + 1024 bytes fit into a pile behind its version record.
+        let members: Vec<usize> = (0..5u8)
+            .map(|i| set.insert(&Bytes::from_source(vec![i; 1000])).unwrap().1)
+            .collect();
+        assert_eq!(members, vec![0, 0, 0, 1, 1]);
+        let (first, member) = set.insert(&Bytes::from_source(vec![0u8; 1000])).unwrap();
+        assert_eq!(member, 0);
+        assert_eq!(set.len(), 5);
+        assert!(matches!(
+            set.insert(&Bytes::from_source(vec![0u8; MAX_PILE_SIZE])),
+            Err(InsertError::BlobTooLarge { .. })
+        ));
+        drop(set);
+
+        let set: PileSet<MAX_PILE_SIZE> = PileSet::open(tmp_dir.path()).unwrap();
+        assert_eq!(set.member_count(), 2);
+        assert_eq!(set.len(), 5);
+        assert_eq!(&*set.get(&first).unwrap().unwrap(), &[0u8; 1000][..]);
+        assert!(set.get(&[0; 32]).unwrap().is_none());
+        let (_, member) = set.insert(&Bytes::from_source(vec![5u8; 1000])).unwrap();
+        assert_eq!(member, 1);
+        drop(set);
+
+        let members = tmp_dir.path();
+        std::fs::rename(members.join("1.pile"), members.join("3.pile")).unwrap();
+        assert!(matches!(
+            PileSet::<MAX_PILE_SIZE>::open(members),
+            Err(LoadError::MissingMember(1))
+        ));
+    }
+
+    #[test]
+    fn enormous_lengths_fail_gracefully() {
+        let (tmp_dir, tmp_pile, pile) = temp_pile();
+        let hash = pile.insert_blob(&Bytes::from_source(vec![1u8; 100])).unwrap();
+        drop(pile);
+
+        // A header claiming more bytes than any file could hold, followed by some bytes.
+        let header = BlobHeader::new(0, u64::MAX, [0; 32]);
+        let mut file = OpenOptions::new().append(true).open(&tmp_pile).unwrap();
+        file.write_all(header.as_bytes()).unwrap();
+        file.write_all(&[1u8; 128]).unwrap();
+        drop(file);
+
+        assert!(matches!(
+            Pile::<MAX_PILE_SIZE>::load(&tmp_pile),
+            Err(LoadError::UnexpectedEndOfFile { offset: 256, .. })
+        ));
+        let (pile, report) = Pile::<MAX_PILE_SIZE>::load_lenient(&tmp_pile, false).unwrap();
+        assert_eq!(report.ignored_bytes, 64 + 128);
+        assert_eq!(&*pile.get_blob(&hash).unwrap().unwrap(), &[1u8; 100][..]);
+
+        // Appending to a pile with a limit close to `usize::MAX` must not wrap around.
+        let options = LoadOptions {
+            growable: true,
+            ..LoadOptions::new(usize::MAX / 64 * 64)
+        };
+        let huge_pile = tmp_dir.path().join("huge.pile");
+        let (huge, _) = Pile::<MAX_PILE_SIZE>::load_path(&huge_pile, options).unwrap();
+        assert!(matches!(
+            huge.appended_length(usize::MAX - 63, 128),
+            Err(InsertError::PileTooLarge)
+        ));
+        assert_eq!(blob_record_length(usize::MAX, 64), usize::MAX);
+    }
+
+    #[test]
+    fn import_length_prefixed_blobs() {
+        let (_tmp_dir, _, pile) = temp_pile();
+
+        let mut dump = Vec::new();
+        for blob in [&[1u8; 100][..], &[], &[2u8; 10]] {
+            dump.extend_from_slice(&(blob.len() as u64).to_le_bytes());
+            dump.extend_from_slice(blob);
+        }
+        let hashes = pile.import_lengthprefixed(&dump[..]).unwrap();
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(&*pile.get_blob(&hashes[0]).unwrap().unwrap(), &[1u8; 100][..]);
+        assert_eq!(&*pile.get_blob(&hashes[2]).unwrap().unwrap(), &[2u8; 10][..]);
+
+        // The stream ends in the middle of the last blob.
+        let truncated = &dump[..dump.len() - 1];
+        match pile.import_lengthprefixed(truncated) {
+            Err(InsertError::ImportInterrupted { imported, error }) => {
+                assert_eq!(imported, hashes[..2]);
+                assert!(matches!(*error, InsertError::IoError(_)));
+            }
+            _ => panic!("expected an interrupted import"),
+        }
+
+        let enormous = u64::MAX.to_le_bytes();
+        assert!(matches!(
+            pile.import_lengthprefixed(&enormous[..]),
+            Err(InsertError::ImportInterrupted { error, .. })
+                if matches!(*error, InsertError::BlobTooLarge { .. })
+        ));
+
+        // Large blobs are streamed, a truncated one isn't stored.
+        let large: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let mut dump = (large.len() as u64).to_le_bytes().to_vec();
+        dump.extend_from_slice(&large);
+        let length = pile.used();
+        assert!(pile.import_lengthprefixed(&dump[..dump.len() - 1]).is_err());
+        assert_eq!(pile.used(), length);
+        let hashes = pile.import_lengthprefixed(&dump[..]).unwrap();
+        assert_eq!(&*pile.get_blob(&hashes[0]).unwrap().unwrap(), &large[..]);
     }
 }